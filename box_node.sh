@@ -1,6 +1,6 @@
 dfx ledger fabricate-cycles --canister miner_backend --all
 cargo build --release --target wasm32-unknown-unknown
-dfx deploy miner_backend
+dfx deploy miner_backend --argument "(opt variant { Init = record { ledger_canister_id = principal \"$(dfx canister id icp_ledger_canister)\" } })"
 dfx canister call miner_backend register Console
 dfx canister call icp_ledger_canister icrc2_approve '(
   record {
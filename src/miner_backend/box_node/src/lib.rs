@@ -1,9 +1,8 @@
-use types as T;
 use ic_cdk::api;
 
 
 thread_local! {
-   static SUB_ACCOUNT: std::cell::RefCell<Vec<u8>> = std::cell::RefCell::new(Vec::new());
+   static SUB_ACCOUNT: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
 }
 
 #[ic_cdk::update]
@@ -20,7 +19,7 @@ async fn get_principal() -> String {
         let sub_string = format!("{:?}", *sub_bytes); 
         sub_string
     });  
-    return api::id().to_string() + " "  + &result;
+    api::id().to_string() + " "  + &result
 }
 
 #[ic_cdk::update]
@@ -1,10 +1,8 @@
-use types as T;
 use ic_cdk::api;
 use std::cell::RefCell;
-use std::time::Duration;
 
 thread_local! {
-    static SUB_ACCOUNT: RefCell<Vec<u8>> = RefCell::new(Vec::new());   
+    static SUB_ACCOUNT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };   
 
 }
 
@@ -23,7 +21,7 @@ async fn get_info() -> String {
         let sub_string = format!("{:?}", *sub_bytes); 
         sub_string
     });  
-    return api::id().to_string() + " MINER " + &result;
+    api::id().to_string() + " MINER " + &result
 }
 
 #[ic_cdk::update]
@@ -0,0 +1,158 @@
+use crate::tokens;
+use crate::{LOTTERY_TIME, MINER_TIME, MIN_MINER_COST};
+use candid::Nat;
+use types::{self as T};
+
+/// The rules every box had before they became configurable.
+pub fn default_config(token: &T::TokenInfo) -> T::BoxConfig {
+    T::BoxConfig {
+        duration_secs: LOTTERY_TIME,
+        miner_duration_secs: MINER_TIME,
+        prize_pct: 25,
+        creator_pct: 65,
+        admin_pct: 10,
+        min_miner_stake: tokens::scale_from_e8s(MIN_MINER_COST, token).into(),
+        max_miner_stake: None,
+        max_miners: None,
+        draw: Some(T::DrawMode::StakeWeighted),
+        prize_table: None,
+        randomness: None,
+        rollover_pct: None,
+    }
+}
+
+pub fn prize_table(config: &T::BoxConfig) -> T::PrizeTable {
+    config.prize_table.clone().unwrap_or(T::PrizeTable::Equal(1))
+}
+
+pub fn places(table: &T::PrizeTable) -> usize {
+    match table {
+        T::PrizeTable::Tiered(shares) => shares.len(),
+        T::PrizeTable::Equal(winners) => *winners as usize,
+    }
+}
+
+/// Gross prizes for the first `filled` places. The shares of places nobody
+/// could fill, and rounding dust, go to 1st place.
+pub fn split_prizes(table: &T::PrizeTable, pot: &Nat, filled: usize) -> Vec<Nat> {
+    let filled = filled.min(places(table));
+    if filled == 0 {
+        return Vec::new();
+    }
+    let mut prizes: Vec<Nat> = (0..filled)
+        .map(|place| match table {
+            T::PrizeTable::Tiered(shares) => pot.clone() * shares[place] as u32 / 100u32,
+            T::PrizeTable::Equal(winners) => pot.clone() / *winners,
+        })
+        .collect();
+    let rest = prizes[1..].iter().fold(Nat::from(0u32), |sum, prize| sum + prize.clone());
+    prizes[0] = pot.clone() - rest;
+    prizes
+}
+
+pub fn config_of(box_info: &T::BoxInfo) -> T::BoxConfig {
+    match &box_info.config {
+        Some(config) => config.clone(),
+        None => T::BoxConfig {
+            draw: Some(T::DrawMode::Uniform),
+            ..default_config(&tokens::get_or_unknown(tokens::box_ledger(box_info)))
+        },
+    }
+}
+
+/// Checks a requested config against the admin bounds. `floor` is the
+/// smallest miner stake the backend accepts in the box's token.
+pub fn validate(config: &T::BoxConfig, bounds: &T::BoxConfigBounds, floor: &Nat) -> Result<(), T::BackendError> {
+    let invalid = |reason: String| Err(T::BackendError::InvalidBoxConfig(reason));
+    if config.duration_secs < bounds.min_duration_secs || config.duration_secs > bounds.max_duration_secs {
+        return invalid(format!("Duration must be within {}..={} seconds", bounds.min_duration_secs, bounds.max_duration_secs));
+    }
+    if config.miner_duration_secs < bounds.min_miner_duration_secs || config.miner_duration_secs > config.duration_secs {
+        return invalid(format!("Miner duration must be within {}..={} seconds", bounds.min_miner_duration_secs, config.duration_secs));
+    }
+    if config.prize_pct as u32 + config.creator_pct as u32 + config.admin_pct as u32 != 100 {
+        return invalid("Percentages must add up to 100".to_string());
+    }
+    if config.admin_pct < bounds.min_admin_pct {
+        return invalid(format!("Admin share must be at least {}%", bounds.min_admin_pct));
+    }
+    if config.creator_pct > bounds.max_creator_pct {
+        return invalid(format!("Creator share must be at most {}%", bounds.max_creator_pct));
+    }
+    if config.min_miner_stake.0 < *floor {
+        return invalid(format!("Minimum miner stake must be at least {}", floor));
+    }
+    if let Some(max) = &config.max_miner_stake {
+        if *max < config.min_miner_stake {
+            return invalid("Maximum miner stake is below the minimum".to_string());
+        }
+    }
+    match &config.prize_table {
+        Some(T::PrizeTable::Tiered(shares)) => {
+            if shares.is_empty() || shares.len() > T::MAX_PRIZE_PLACES || shares.contains(&0) {
+                return invalid(format!("A prize table needs 1..={} places with a share each", T::MAX_PRIZE_PLACES));
+            }
+            if shares.iter().map(|share| *share as u32).sum::<u32>() != 100 {
+                return invalid("Prize shares must add up to 100".to_string());
+            }
+        }
+        Some(T::PrizeTable::Equal(winners)) if *winners == 0 || *winners as usize > T::MAX_PRIZE_PLACES => {
+            return invalid(format!("A box can have 1..={} winners", T::MAX_PRIZE_PLACES));
+        }
+        _ => {}
+    }
+    if config.rollover_pct.unwrap_or(0) > 100 {
+        return invalid("Rollover share must be at most 100%".to_string());
+    }
+    match config.max_miners {
+        Some(0) => invalid("A box needs room for at least one miner".to_string()),
+        Some(max_miners) if max_miners > bounds.max_miners => {
+            invalid(format!("A box can hold at most {} miners", bounds.max_miners))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A template opens a new box, and so takes a node canister, every time the
+/// last one ends; it may not do that more often than this.
+pub const MIN_TEMPLATE_DURATION_SECS: u64 = 60 * 60;
+
+pub fn validate_template(config: &T::BoxConfig) -> Result<(), T::BackendError> {
+    if config.duration_secs < MIN_TEMPLATE_DURATION_SECS {
+        return Err(T::BackendError::InvalidBoxConfig(format!(
+            "A template's boxes must last at least {} seconds",
+            MIN_TEMPLATE_DURATION_SECS
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nats(values: &[u32]) -> Vec<Nat> {
+        values.iter().map(|value| Nat::from(*value)).collect()
+    }
+
+    #[test]
+    fn tiered_prizes_follow_the_shares() {
+        let table = T::PrizeTable::Tiered(vec![50, 30, 20]);
+        assert_eq!(split_prizes(&table, &Nat::from(1_000u32), 3), nats(&[500, 300, 200]));
+    }
+
+    #[test]
+    fn unfilled_places_go_to_first_place() {
+        let table = T::PrizeTable::Tiered(vec![50, 30, 20]);
+        assert_eq!(split_prizes(&table, &Nat::from(1_000u32), 2), nats(&[700, 300]));
+        assert_eq!(split_prizes(&table, &Nat::from(1_000u32), 5), nats(&[500, 300, 200]));
+        assert!(split_prizes(&table, &Nat::from(1_000u32), 0).is_empty());
+    }
+
+    #[test]
+    fn equal_prizes_give_the_dust_to_first_place() {
+        let table = T::PrizeTable::Equal(3);
+        assert_eq!(split_prizes(&table, &Nat::from(1_000u32), 3), nats(&[334, 333, 333]));
+        assert_eq!(split_prizes(&table, &Nat::from(1_000u32), 1), nats(&[1_000]));
+    }
+}
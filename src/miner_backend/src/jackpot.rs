@@ -0,0 +1,145 @@
+use crate::scheduler;
+use crate::state::{self, JACKPOTS, JACKPOT_DRAWS, JACKPOT_HISTORY, LEDGER_JACKPOT_HISTORY};
+use candid::{Nat, Principal};
+use ic_cdk::api::{self, print};
+use types::{self as T};
+
+// Events returned with a jackpot by `get_jackpot`.
+const HISTORY_LEN: usize = 50;
+
+pub fn get(ledger: &Principal) -> Option<T::Jackpot> {
+    JACKPOTS.with(|jackpots| jackpots.borrow().get(ledger))
+}
+
+pub fn list() -> Vec<T::Jackpot> {
+    JACKPOTS.with(|jackpots| jackpots.borrow().iter().map(|(_, jackpot)| jackpot).collect())
+}
+
+pub fn put(jackpot: T::Jackpot) {
+    JACKPOTS.with(|jackpots| jackpots.borrow_mut().insert(jackpot.ledger, jackpot));
+}
+
+/// The jackpot of `ledger` with its latest events.
+pub fn view(ledger: &Principal) -> Option<T::JackpotView> {
+    let jackpot = get(ledger)?;
+    let ids: Vec<u64> = LEDGER_JACKPOT_HISTORY.with(|index| {
+        index
+            .borrow()
+            .range((*ledger, 0)..=(*ledger, u64::MAX))
+            .rev()
+            .take(HISTORY_LEN)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    let history = JACKPOT_HISTORY.with(|history| {
+        let history = history.borrow();
+        ids.into_iter().filter_map(|id| history.get(id)).collect()
+    });
+    Some(T::JackpotView { jackpot, history })
+}
+
+/// Books a rollover or contribution that reached the jackpot subaccount.
+/// The first one in a token opens its jackpot and schedules the first draw.
+pub fn credit(ledger: Principal, kind: T::JackpotEventKind) {
+    let amount = match &kind {
+        T::JackpotEventKind::Rollover { amount, .. } | T::JackpotEventKind::Contribution { amount, .. } => amount.clone(),
+        T::JackpotEventKind::Draw { .. } => return,
+    };
+    let mut jackpot = get(&ledger).unwrap_or_else(|| open(ledger));
+    jackpot.balance = jackpot.balance + amount;
+    put(jackpot);
+    record(ledger, kind);
+}
+
+/// Appends a draw transcript and returns its id.
+pub fn record_draw(mut transcript: T::JackpotDrawTranscript) -> u64 {
+    JACKPOT_DRAWS.with(|draws| {
+        let draws = draws.borrow();
+        transcript.id = draws.len();
+        draws.append(&transcript).expect("Failed to append to JACKPOT_DRAWS")
+    })
+}
+
+pub fn get_draw(id: u64) -> Option<T::JackpotDrawTranscript> {
+    JACKPOT_DRAWS.with(|draws| draws.borrow().get(id))
+}
+
+/// Books the outcome of a finished draw and schedules the next one. A prize
+/// that could not be paid stays in the jackpot, and the period stays open so
+/// its miners get another chance.
+pub fn close_draw(ledger: Principal, settlement: &T::Settlement) {
+    let mut jackpot = match get(&ledger) {
+        Some(jackpot) => jackpot,
+        None => return,
+    };
+    let now = api::time();
+    let paid = settlement.payouts.iter().find_map(|payout| match payout.status {
+        T::PayoutStatus::Done { .. } => Some(payout.amount.0.clone() + payout.fee.clone().unwrap_or_else(|| Nat::from(0u32))),
+        _ => None,
+    });
+    let draw_id = jackpot.pending_draw.take();
+    match (jackpot.pending_winner.take(), paid) {
+        (Some(winner), Some(paid)) => {
+            jackpot.rounds += 1;
+            jackpot.balance = if paid >= jackpot.balance.0 { Nat::from(0u32) } else { jackpot.balance.0 - paid }.into();
+            jackpot.period_start = now;
+            print(format!("Jackpot of {} won by {}", ledger, winner.miner_id));
+            record(ledger, T::JackpotEventKind::Draw { round: jackpot.rounds, winner, draw_id });
+        }
+        (Some(winner), None) => {
+            print(format!("Jackpot prize of {} to {} was not paid", ledger, winner.miner_id));
+        }
+        (None, _) => {
+            jackpot.period_start = now;
+        }
+    }
+    jackpot.next_draw_at = now + interval_nanos();
+    scheduler::schedule(T::JobKind::JackpotDraw(ledger), jackpot.next_draw_at);
+    put(jackpot);
+}
+
+fn open(ledger: Principal) -> T::Jackpot {
+    let now = api::time();
+    let next_draw_at = now + interval_nanos();
+    print(format!("Opening the jackpot of {}", ledger));
+    scheduler::schedule(T::JobKind::JackpotDraw(ledger), next_draw_at);
+    T::Jackpot {
+        ledger,
+        balance: Nat::from(0u32).into(),
+        period_start: now,
+        next_draw_at,
+        rounds: 0,
+        pending_winner: None,
+        pending_draw: None,
+    }
+}
+
+fn record(ledger: Principal, kind: T::JackpotEventKind) {
+    let id = JACKPOT_HISTORY.with(|history| {
+        history
+            .borrow()
+            .append(&T::JackpotEvent { ledger, kind, timestamp: api::time() })
+            .expect("Failed to append to JACKPOT_HISTORY")
+    });
+    LEDGER_JACKPOT_HISTORY.with(|index| index.borrow_mut().insert((ledger, id), ()));
+}
+
+/// Indexes the history recorded before it was indexed by ledger; run once
+/// by the v7 -> v8 migration.
+pub fn backfill_index() {
+    JACKPOT_HISTORY.with(|history| {
+        let history = history.borrow();
+        LEDGER_JACKPOT_HISTORY.with(|index| {
+            let mut index = index.borrow_mut();
+            for id in 0..history.len() {
+                if let Some(event) = history.get(id) {
+                    index.insert((event.ledger, id), ());
+                }
+            }
+        });
+    });
+}
+
+fn interval_nanos() -> u64 {
+    state::jackpot_settings().draw_interval_secs.saturating_mul(1_000_000_000)
+}
@@ -0,0 +1,111 @@
+use crate::state::{BOX_JOURNAL, JOURNAL, USER_JOURNAL};
+use candid::{Nat, Principal};
+use ic_cdk::api;
+use types::{self as T};
+
+const MAX_PAGE: u64 = 100;
+
+/// One ledger movement to journal; `record` adds its id and timestamp.
+pub struct NewEntry {
+    pub ledger: Principal,
+    pub reason: T::JournalReason,
+    pub amount: T::TokenAmount,
+    pub from: T::ICRCAccount,
+    pub to: T::ICRCAccount,
+    pub block_index: Nat,
+    pub box_id: Option<T::BoxId>,
+    pub miner_id: Option<T::MinerId>,
+}
+
+/// Appends one ledger movement to the journal and returns its id.
+pub fn record(new: NewEntry) -> u64 {
+    let entry = JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        let entry = T::JournalEntry {
+            id: journal.len(),
+            reason: new.reason,
+            amount: new.amount,
+            from: new.from,
+            to: new.to,
+            block_index: new.block_index,
+            timestamp: api::time(),
+            box_id: new.box_id,
+            miner_id: new.miner_id,
+            ledger: Some(new.ledger),
+        };
+        journal.append(&entry).expect("Failed to append to JOURNAL");
+        entry
+    });
+    index(&entry);
+    entry.id
+}
+
+/// Adds `entry` to the per-user and per-box indexes.
+pub fn index(entry: &T::JournalEntry) {
+    USER_JOURNAL.with(|users| {
+        let mut users = users.borrow_mut();
+        users.insert((entry.from.owner, entry.id), ());
+        users.insert((entry.to.owner, entry.id), ());
+    });
+    if let Some(box_id) = entry.box_id {
+        BOX_JOURNAL.with(|boxes| boxes.borrow_mut().insert((box_id, entry.id), ()));
+    }
+}
+
+/// Indexes a journal written before the indexes existed; run once by the
+/// v6 -> v7 migration.
+pub fn backfill_index() {
+    JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        for entry in (0..journal.len()).filter_map(|id| journal.get(id)) {
+            index(&entry);
+        }
+    });
+}
+
+/// Newest entries first, skipping `offset` of them.
+pub fn page(offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    let ids = JOURNAL.with(|journal| (0..journal.borrow().len()).rev().skip(offset as usize).take(page_len(limit)).collect());
+    entries(ids)
+}
+
+/// The entries `user` paid or was paid in, newest first.
+pub fn user_page(user: Principal, offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    let ids = USER_JOURNAL.with(|users| {
+        users
+            .borrow()
+            .range((user, 0)..=(user, u64::MAX))
+            .rev()
+            .skip(offset as usize)
+            .take(page_len(limit))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    entries(ids)
+}
+
+/// The entries of `box_id` and its miners, newest first.
+pub fn box_page(box_id: T::BoxId, offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    let ids = BOX_JOURNAL.with(|boxes| {
+        boxes
+            .borrow()
+            .range((box_id, 0)..=(box_id, u64::MAX))
+            .rev()
+            .skip(offset as usize)
+            .take(page_len(limit))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    entries(ids)
+}
+
+fn page_len(limit: u64) -> usize {
+    limit.min(MAX_PAGE) as usize
+}
+
+fn entries(ids: Vec<u64>) -> Vec<T::JournalEntry> {
+    JOURNAL.with(|journal| {
+        let journal = journal.borrow();
+        ids.into_iter().filter_map(|id| journal.get(id)).collect()
+    })
+}
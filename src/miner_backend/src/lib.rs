@@ -2,134 +2,246 @@ use ic_cdk::api::call::call;
 use ic_cdk::api;
 use ic_cdk::api::management_canister::main::{
     CreateCanisterArgument, create_canister, InstallCodeArgument, install_code, CanisterInstallMode,
+    CanisterIdRecord, uninstall_code,
 };
 use ic_cdk::api::management_canister::provisional::CanisterSettings;
 use candid::{Nat, Principal};
-use std::collections::BTreeMap;
 use types::{self as T};
-use ic_cdk_timers::{set_timer};
-use std::time::Duration;
+use types::ledger::{Ledger, LedgerFailure};
 use ic_cdk::api::print;
 use ic_cdk::api::management_canister::main::raw_rand;
 
+mod box_rules;
+mod jackpot;
+mod journal;
+mod migrations;
+mod scheduler;
+mod settlement;
+mod state;
+mod templates;
+mod tokens;
+
+use state::{USERS, BOXES, MINERS, BOX_MINER, BLOCKED, BOX_RESULTS, DRAWS};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
 const BOX_NODE_WASM: &[u8] = include_bytes!("../../../target/wasm32-unknown-unknown/release/box_node.wasm");
 const MINER_NODE_WASM: &[u8] = include_bytes!("../../../target/wasm32-unknown-unknown/release/miner_node.wasm");
 
 const CANISTER_CYCLES: u128 = 100_000_000_000;
+// Minimum stakes in the smallest unit of an 8-decimal token, scaled per token.
 const MIN_BOX_COST: u64 = 500_000_000;
 const MIN_MINER_COST: u64 = 500_000;
-const FEE: u64 = 10_000;
-const LOTTERY_TIME: u64 = 1 * 1 * 1 * 60; //days hours mins seconds
-const MINER_TIME: u64 = 1 * 1 * 1 * 30; //days hours mins seconds
-
 thread_local! {
-    static USERS: std::cell::RefCell<BTreeMap<String, T::User>> = std::cell::RefCell::new(BTreeMap::new());
-    static BOXES: std::cell::RefCell<BTreeMap<String, T::BoxInfo>> = std::cell::RefCell::new(BTreeMap::new());
-    static MINERS: std::cell::RefCell<BTreeMap<String, T::Miner>> = std::cell::RefCell::new(BTreeMap::new());
-    static BOX_MINER: std::cell::RefCell<BTreeMap<String, String>> = std::cell::RefCell::new(BTreeMap::new());
-    static SUB_INDEX: std::cell::RefCell<u32> = std::cell::RefCell::new(0);
+    // Places held in a box by miner entries still waiting on the ledger.
+    // Heap only, like the scheduler's running jobs.
+    static RESERVED_SLOTS: RefCell<BTreeMap<T::BoxId, u32>> = const { RefCell::new(BTreeMap::new()) };
 }
 
-#[ic_cdk::pre_upgrade]
-fn pre_upgrade() {
-    USERS.with(|users| {
-        BOXES.with(|boxes| {
-            MINERS.with(|miners| {
-                BOX_MINER.with(|box_miner| {
-                    SUB_INDEX.with(|sub_index| {
-                        ic_cdk::storage::stable_save((
-                            users.borrow().clone(),
-                            boxes.borrow().clone(),
-                            miners.borrow().clone(),
-                            box_miner.borrow().clone(),
-                            *sub_index.borrow(),
-                        )).expect("Failed to save state to stable memory");
-                    });                   
-                });                
-            });
-        });
-    });
+// Defaults for boxes opened without a `BoxConfig`.
+const LOTTERY_TIME: u64 = 60; // seconds
+const MINER_TIME: u64 = 30; // seconds
+// Most of a miner stake the jackpot may take before the box's split.
+const MAX_JACKPOT_CONTRIBUTION_PCT: u8 = 50;
+
+#[ic_cdk::init]
+fn init(args: Option<T::BackendArgs>) {
+    match args {
+        Some(T::BackendArgs::Init(init_args)) => {
+            state::set_config(T::Config { ledger_canister_id: init_args.ledger_canister_id });
+        }
+        Some(T::BackendArgs::Upgrade(_)) => ic_cdk::trap("Expected Init args on install"),
+        None => {}
+    }
+    tokens::ensure_default();
+    scheduler::arm();
 }
 
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    let (users, boxes, miners, box_miner, sub_index): (
-        BTreeMap<String, T::User>,
-        BTreeMap<String, T::BoxInfo>,
-        BTreeMap<String, T::Miner>,
-        BTreeMap<String, String>,
-        u32,
-    ) = ic_cdk::storage::stable_restore().expect("Failed to restore state from stable memory");
-
-    USERS.with(|u| *u.borrow_mut() = users);
-    BOXES.with(|b| *b.borrow_mut() = boxes);
-    MINERS.with(|m| *m.borrow_mut() = miners);
-    /*let cloned_miners: Vec<(String, T::Miner)> = MINERS.with(|m| {
-        m.borrow()
-            .iter()
-            .map(|(id, info)| (id.clone(), info.clone()))
-            .collect()
-    });    
-    for (miner_id, miner_info) in cloned_miners {
-        if(miner_info.is_end == false)
-        {
-            print(format!("Starting miner timer {:?}", miner_id));    
-            
-            let mut seconds: u64 = 2; 
-            print(format!("from_secs: {:?}", seconds));
-            if seconds < 1 {
-                seconds = 1;
-            }    
-            set_timer(Duration::from_secs(seconds), move || {
-                ic_cdk::spawn(async move {
-                    miner_end(miner_info.clone()).await;
-                });
-            });
-        }   
-    }*/
+fn post_upgrade(args: Option<T::BackendArgs>) {
+    migrations::run();
+    match args {
+        Some(T::BackendArgs::Upgrade(Some(upgrade_args))) => {
+            let mut config = state::config();
+            if let Some(ledger_canister_id) = upgrade_args.ledger_canister_id {
+                config.ledger_canister_id = ledger_canister_id;
+            }
+            state::set_config(config);
+        }
+        Some(T::BackendArgs::Upgrade(None)) | None => {}
+        Some(T::BackendArgs::Init(_)) => ic_cdk::trap("Expected Upgrade args on upgrade"),
+    }
+    tokens::ensure_default();
+    scheduler::ensure_settlement_jobs();
+    scheduler::arm();
+}
 
-    BOX_MINER.with(|bm| *bm.borrow_mut() = box_miner);
-    SUB_INDEX.with(|si| *si.borrow_mut() = sub_index);
+#[ic_cdk::query]
+fn get_config() -> T::Config {
+    state::config()
 }
 
+#[ic_cdk::query]
+fn list_tokens() -> Vec<T::TokenInfo> {
+    tokens::list()
+}
 
+#[ic_cdk::update]
+async fn add_token(ledger: Principal) -> Result<T::TokenInfo, T::BackendError> {
+    require_controller()?;
+    tokens::add(ledger).await
+}
 
-fn get_user_by_princ(principal: String) -> Option<T::User> {
-    USERS.with(|users| users.borrow().get(&principal).cloned())
+#[ic_cdk::update]
+fn disable_token(ledger: Principal) -> Result<(), T::BackendError> {
+    require_controller()?;
+    tokens::disable(ledger)
 }
 
 #[ic_cdk::query]
-fn get_user() -> Result<T::User, String>{
-    let principal_text = ic_cdk::caller().to_text();
-    let maybe_user = get_user_by_princ(principal_text.clone());
+fn get_box_bounds() -> T::BoxConfigBounds {
+    state::box_bounds()
+}
+
+#[ic_cdk::update]
+fn set_box_bounds(bounds: T::BoxConfigBounds) -> Result<(), T::BackendError> {
+    require_controller()?;
+    state::set_box_bounds(bounds);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_jackpot_settings() -> T::JackpotSettings {
+    state::jackpot_settings()
+}
+
+/// The interval takes effect from each jackpot's next draw, the contribution
+/// from the next miner settled.
+#[ic_cdk::update]
+fn set_jackpot_settings(settings: T::JackpotSettings) -> Result<(), T::BackendError> {
+    require_controller()?;
+    if settings.draw_interval_secs == 0 {
+        return Err(T::BackendError::InvalidJackpotSettings("Draw interval must be positive".to_string()));
+    }
+    if settings.contribution_pct.unwrap_or(0) > MAX_JACKPOT_CONTRIBUTION_PCT {
+        return Err(T::BackendError::InvalidJackpotSettings(format!("Contribution must be at most {}%", MAX_JACKPOT_CONTRIBUTION_PCT)));
+    }
+    state::set_jackpot_settings(settings);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn block_user(user: Principal) -> Result<(), T::BackendError> {
+    require_controller()?;
+    BLOCKED.with(|blocked| blocked.borrow_mut().insert(user, api::time()));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unblock_user(user: Principal) -> Result<(), T::BackendError> {
+    require_controller()?;
+    BLOCKED.with(|blocked| blocked.borrow_mut().remove(&user));
+    Ok(())
+}
+
+fn require_controller() -> Result<(), T::BackendError> {
+    if api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(T::BackendError::NotAuthorized)
+    }
+}
+
+fn get_user_by_princ(principal: Principal) -> Option<T::User> {
+    USERS.with(|users| users.borrow().get(&principal))
+}
+
+#[ic_cdk::query]
+fn get_user() -> Result<T::User, T::BackendError>{
+    let maybe_user = get_user_by_princ(ic_cdk::caller());
     if let Some(user) = maybe_user {
         Ok(user)
     } else {
-        return Err("User not exist".to_string())
+        Err(T::BackendError::NotRegistered)
     }
 }
 
 #[ic_cdk::query]
-fn show_all_users() -> Vec<(String, T::User)> {
-    USERS.with(|users| users.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+fn list_pending_jobs() -> Vec<T::Job> {
+    scheduler::pending_jobs()
+}
+
+#[ic_cdk::query]
+fn get_settlement(kind: T::JobKind) -> Option<T::Settlement> {
+    settlement::get_settlement(&kind)
 }
 
+/// Runs a settlement that gave up once more, e.g. after the ledger issue
+/// that failed it was fixed.
 #[ic_cdk::update]
-fn register(nickname: String) -> Result<T::User, String>  {
-    let principal_text = ic_cdk::caller().to_text();
-    let maybe_user = get_user_by_princ(principal_text.clone());
+fn requeue_settlement(kind: T::JobKind) -> Result<(), T::BackendError> {
+    require_controller()?;
+    settlement::requeue(kind)?;
+    scheduler::schedule(kind, api::time());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_box_result(box_id: T::BoxId) -> Option<T::BoxResult> {
+    BOX_RESULTS.with(|results| results.borrow().get(&box_id))
+}
+
+#[ic_cdk::query]
+fn get_draw_transcript(box_id: T::BoxId) -> Option<T::DrawTranscript> {
+    DRAWS.with(|draws| draws.borrow().get(&box_id))
+}
+
+#[ic_cdk::query]
+fn get_jackpot(ledger: Option<Principal>) -> Option<T::JackpotView> {
+    jackpot::view(&ledger.unwrap_or_else(|| state::config().ledger_canister_id))
+}
+
+#[ic_cdk::query]
+fn get_jackpot_draw(id: u64) -> Option<T::JackpotDrawTranscript> {
+    jackpot::get_draw(id)
+}
+
+#[ic_cdk::query]
+fn get_journal(offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    journal::page(offset, limit)
+}
+
+#[ic_cdk::query]
+fn get_user_journal(user: Principal, offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    journal::user_page(user, offset, limit)
+}
+
+#[ic_cdk::query]
+fn get_box_journal(box_id: T::BoxId, offset: u64, limit: u64) -> Vec<T::JournalEntry> {
+    journal::box_page(box_id, offset, limit)
+}
+
+#[ic_cdk::query]
+fn show_all_users() -> Vec<(Principal, T::User)> {
+    USERS.with(|users| users.borrow().iter().collect())
+}
+
+#[ic_cdk::update]
+fn register(nickname: String) -> Result<T::User, T::BackendError>  {
+    let principal = ic_cdk::caller();
+    let maybe_user = get_user_by_princ(principal);
     if maybe_user.is_some() {
-        return Err("User already exist".to_string())
+        return Err(T::BackendError::AlreadyRegistered)
     }
 
-    let user = T::User { nickname }.clone();    
-    USERS.with(|users: &std::cell::RefCell<BTreeMap<String, T::User>>| {
-        users.borrow_mut().insert(principal_text, user.clone());
+    let user = T::User { nickname: T::Nickname::new(nickname)? };
+    USERS.with(|users| {
+        users.borrow_mut().insert(principal, user.clone());
     });
     Ok(user)
 }
 
-async fn create_box_canister() -> String {
+async fn create_node_canister() -> Result<Principal, String> {
     let create_args: CreateCanisterArgument = CreateCanisterArgument {
         settings: Some(CanisterSettings {
             controllers: Some(vec![ic_cdk::id()]),
@@ -141,123 +253,139 @@ async fn create_box_canister() -> String {
             wasm_memory_limit: None,
         })
     };
-        
-    let canister_record = create_canister(create_args, CANISTER_CYCLES).await.unwrap();
-    let canister_id = canister_record.0.canister_id;
-    let install_args = InstallCodeArgument {
-        mode: CanisterInstallMode::Install,
-        canister_id,
-        wasm_module: BOX_NODE_WASM.to_vec(),
-        arg: vec![],
-    };
-    install_code(install_args).await.unwrap(); 
-    return canister_id.to_text();
+
+    match create_canister(create_args, CANISTER_CYCLES).await {
+        Ok((canister_record,)) => Ok(canister_record.canister_id),
+        Err(e) => Err(format!("create_canister failed: {:?}", e)),
+    }
 }
 
-async fn create_miner_canister() -> String {
-    let create_args: CreateCanisterArgument = CreateCanisterArgument {
-        settings: Some(CanisterSettings {
-            controllers: Some(vec![ic_cdk::id()]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-            reserved_cycles_limit: None,
-            log_visibility: None,
-            wasm_memory_limit: None,
-        })
+// Creates (or reuses a reclaimed) canister running `wasm` and hands it its
+// subaccount. On failure the canister goes back to the spare pool. Boxes and
+// miners are keyed by their canister id, so a spare is only reused for a
+// kind of node it has not been yet: `reusable` says which ones qualify.
+async fn install_node(wasm: &[u8], sub: Vec<u8>, reusable: fn(Principal) -> bool) -> Result<Principal, String> {
+    let canister_id = match state::take_spare_canister(reusable) {
+        Some(canister_id) => canister_id,
+        None => create_node_canister().await?,
     };
-        
-    let canister_record = create_canister(create_args, CANISTER_CYCLES).await.unwrap();
-    let canister_id = canister_record.0.canister_id;
+    // Reinstall works both on a fresh canister and on a reclaimed one.
     let install_args = InstallCodeArgument {
-        mode: CanisterInstallMode::Install,
+        mode: CanisterInstallMode::Reinstall,
         canister_id,
-        wasm_module: MINER_NODE_WASM.to_vec(),
+        wasm_module: wasm.to_vec(),
         arg: vec![],
     };
-    install_code(install_args).await.unwrap(); 
-    return canister_id.to_text();
+    if let Err(e) = install_code(install_args).await {
+        reclaim_canister(canister_id).await;
+        return Err(format!("install_code failed: {:?}", e));
+    }
+    if let Err(e) = call::<(Vec<u8>,), ()>(canister_id, "init", (sub,)).await {
+        reclaim_canister(canister_id).await;
+        return Err(format!("Init Call failed: {:?}", e));
+    }
+    Ok(canister_id)
 }
 
-
-async fn get_balance(target_principal: Principal, sub: Option<Vec<u8>>) -> Result<u64, String> {
-    let maybe_principal = candid::Principal::from_text(T::LEDGER_CANISTER);
-    match maybe_principal {
-        Ok(ledger_principal) => {
-            let args = T::ICRCAccount {
-                owner: target_principal, 
-                subaccount: sub,
-            };
-            match call::<(T::ICRCAccount,), (Vec<u8>,)>(ledger_principal, "account_identifier", (args,)).await {
-                Ok((account_nat8,)) => {
-                    let balance_args = T::AccountBalanceArgs {                        
-                        account: account_nat8
-                    };
-                    match call::<(T::AccountBalanceArgs,), (T::BalanceArgs,)>(ledger_principal, "account_balance", (balance_args,)).await {
-                        Ok((balance,)) => {
-                            Ok(balance.e8s)
-                        },
-                        Err(e) => Err(format!("account_balance Call failed: {:?}", e)),
-                    }
-                },
-                Err(e) => Err(format!("account_identifier Call failed: {:?}", e)),
-            }
-        }
-        Err(err) => {
-            Err("Failed get balance".to_string())
-        }
+async fn reclaim_canister(canister_id: Principal) {
+    if let Err(e) = uninstall_code(CanisterIdRecord { canister_id }).await {
+        print(format!("uninstall_code {} failed: {:?}", canister_id, e));
     }
+    print(format!("Reclaimed canister {}", canister_id));
+    state::put_spare_canister(canister_id);
 }
 
+fn never_a_box(canister_id: Principal) -> bool {
+    state::get_box(&T::BoxId(canister_id)).is_none()
+}
 
-async fn get_allowance(target_principal: Principal) -> Result<Nat, String> {
-    let maybe_principal = candid::Principal::from_text(T::LEDGER_CANISTER);
-    match maybe_principal {
-        Ok(ledger_principal) => {
-            let args = T::ICRC2AllowanceArgs {
-                account: T::ICRCAccount { owner: target_principal, subaccount: None },
-                spender: T::ICRCAccount { owner: api::id(), subaccount: None },
-            };
-                        
-            match call::<(T::ICRC2AllowanceArgs,), (T::ICRC2Allowance,)>(ledger_principal, "icrc2_allowance", (args,)).await {
-                Ok((balance,)) => {
-                    Ok(balance.allowance)
-                },
-                Err(e) => Err(format!("icrc2_allowance Call failed: {:?}", e)),
-            }            
+fn never_a_miner(canister_id: Principal) -> bool {
+    state::get_miner(&T::MinerId(canister_id)).is_none()
+}
+
+// A settled box or miner no longer needs its node. The canister goes back to
+// the spare pool unless it has already been both a box and a miner, as
+// `install_node` could not reuse it again.
+async fn retire_node(canister_id: Principal) {
+    if never_a_box(canister_id) || never_a_miner(canister_id) {
+        reclaim_canister(canister_id).await;
+    } else if let Err(e) = uninstall_code(CanisterIdRecord { canister_id }).await {
+        print(format!("uninstall_code {} failed: {:?}", canister_id, e));
+    }
+}
+
+// Sends a payment whose box or miner could not be set up back to `payer`,
+// minus the ledger fee.
+async fn refund_payment<L: Ledger>(ledger: &L, amount: Nat, sub: Vec<u8>, reason: T::BackendError, box_id: Option<T::BoxId>, payer: Principal) -> T::BackendError {
+    print(format!("Refunding {} after failed setup: {:?}", amount, reason));
+    let reason = Box::new(reason);
+    let to = T::ICRCAccount { owner: payer, subaccount: None };
+    let (fee, result) = tokens::with_fee_retry(ledger, |fee| {
+        let refund_args = (amount > fee).then(|| T::TransferArg {
+            from_subaccount: Some(sub.clone()),
+            to: to.clone(),
+            amount: amount.clone() - fee.clone(),
+            fee: Some(fee),
+            memo: None,
+            created_at_time: Some(api::time()),
+        });
+        let balance = amount.clone();
+        async move {
+            match refund_args {
+                Some(refund_args) => ledger.transfer(refund_args).await,
+                // The subaccount holds `amount`, which cannot pay for its own refund.
+                None => Err(LedgerFailure::Rejected(T::TransferError::InsufficientFunds { balance })),
+            }
         }
-        Err(err) => {
-            Err("Failed get balance".to_string())
+    })
+    .await;
+    match result {
+        Ok(refund_block) => {
+            journal::record(journal::NewEntry {
+                ledger: ledger.canister_id(),
+                reason: T::JournalReason::Refund,
+                amount: (amount - fee).into(),
+                from: T::ICRCAccount { owner: api::id(), subaccount: Some(sub) },
+                to,
+                block_index: refund_block.clone(),
+                box_id,
+                miner_id: None,
+            });
+            T::BackendError::Refunded { reason, refund_block }
         }
+        Err(e) => T::BackendError::RefundFailed { reason, refund_error: format!("{:?}", e), subaccount: sub },
     }
 }
 
-
+fn account(owner: Principal, subaccount: Option<Vec<u8>>) -> T::ICRCAccount {
+    T::ICRCAccount { owner, subaccount }
+}
 
 #[ic_cdk::update]
-async fn get_my_balance() -> Result<u64, String> {    
-    match get_balance(ic_cdk::caller(), None).await {
-        Ok(balance64) => {            
-            Ok(balance64)
-        },
-        Err(e) => Err(e),
+async fn get_my_balance(ledger: Option<Principal>) -> Result<Nat, T::BackendError> {    
+    let ledger = tokens::client(ledger.unwrap_or_else(|| state::config().ledger_canister_id))?;
+    match ledger.balance_of(account(ic_cdk::caller(), None)).await {
+        Ok(balance) => Ok(balance),
+        Err(e) => Err(T::BackendError::LedgerCall(e)),
     }
 }
 
 #[ic_cdk::update]
-async fn get_my_allowance() -> Result<Nat, String> {    
-    match get_allowance(ic_cdk::caller()).await {
+async fn get_my_allowance(ledger: Option<Principal>) -> Result<Nat, T::BackendError> {    
+    let ledger = tokens::client(ledger.unwrap_or_else(|| state::config().ledger_canister_id))?;
+    match ledger.allowance(account(ic_cdk::caller(), None), account(api::id(), None)).await {
         Ok(balance) => {            
             Ok(balance)
         },
-        Err(e) => Err(e),
+        Err(e) => Err(T::BackendError::LedgerCall(e)),
     }
 }
 
 #[ic_cdk::update]
 fn get_all_boxes() -> Vec<T::BoxWithCount> {
     let mut result = vec![];
-    let user_principal = ic_cdk::caller().to_text();
+    let user_principal = ic_cdk::caller();
+    let now = api::time();
     BOXES.with(|boxes_ref| {
         let boxes = boxes_ref.borrow();
 
@@ -265,259 +393,163 @@ fn get_all_boxes() -> Vec<T::BoxWithCount> {
             if box_info.is_end {
                 continue; 
             }
-            let all_miners = get_active_miners(box_id.clone());
-            let maybe_username = get_user_by_princ(box_info.clone().user);
+            let all_miners = get_box_miners(&box_id);
+            let token = tokens::get_or_unknown(tokens::box_ledger(&box_info));
+            let config = box_rules::config_of(&box_info);
+            let maybe_username = get_user_by_princ(box_info.user);
             let username: String = match maybe_username {
-                Some(user) => user.nickname,
+                Some(user) => user.nickname.as_str().to_string(),
                 None => "Unknown".to_string(),
             };
-            if !all_miners.is_empty() {
-                let user_miners: Vec<T::Miner> = all_miners
+            let user_miners: Vec<T::Miner> = all_miners
                 .iter()
-                .cloned()
                 .filter(|miner| miner.user == user_principal)
-                .collect();
-
-                let active_miners: Vec<T::Miner> = all_miners
-                .iter()
                 .cloned()
-                .filter(|miner| miner.is_end == true)
                 .collect();
-
-                result.push(T::BoxWithCount {
-                    username: username,
-                    miner_count: active_miners.len() as u32,
-                    end_date: box_info.clone().end_date,
-                    reg_date: box_info.clone().reg_date,
-                    canister_id: box_info.clone().canister_id,
-                    user_miners: user_miners
-                });
-            }
-            else {
-                result.push(T::BoxWithCount {
-                    username: username,
-                    miner_count: 0,
-                    end_date: box_info.clone().end_date,
-                    reg_date: box_info.clone().reg_date,
-                    canister_id: box_info.clone().canister_id,
-                    user_miners: Vec::new()
-                });
-            }
+            let active_miner_count = all_miners
+                .iter()
+                .filter(|miner| miner.status_at(now) == T::MinerStatus::Active)
+                .count();
+            let total_stake = all_miners
+                .iter()
+                .filter(|miner| miner.is_eligible(now))
+                .fold(Nat::from(0u32), |sum, miner| sum + stake_of(miner, &config));
+
+            result.push(T::BoxWithCount {
+                username,
+                miner_count: all_miners.len() as u32,
+                end_date: box_info.clone().end_date,
+                reg_date: box_info.clone().reg_date,
+                canister_id: box_info.canister_id,
+                user_miners,
+                ledger_canister_id: token.ledger_canister_id,
+                token_symbol: token.symbol.clone(),
+                token_decimals: token.decimals,
+                token_fee: token.fee.clone(),
+                config: config.clone(),
+                seed_commit: box_info.seed_commit.clone(),
+                active_miner_count: active_miner_count as u32,
+                total_stake: total_stake.into(),
+            });
         }
     });
-    result.sort_by(|a, b| b.end_date.cmp(&a.end_date));
+    result.sort_by_key(|box_info| std::cmp::Reverse(box_info.end_date));
     result
 }
 
-fn get_active_miners(box_id: String) -> Vec<T::Miner> {
+// Every miner ever opened in `box_id`, whatever its status.
+fn get_box_miners(box_id: &T::BoxId) -> Vec<T::Miner> {
     MINERS.with(|miners_ref| {
         let miners = miners_ref.borrow();
         miners
-            .values()
+            .iter()
+            .map(|(_, m)| m)
             .filter(|m| m.box_id == *box_id)
-            .cloned()
             .collect()
     })
 }
 
-async fn miner_end(miner: T::Miner) -> () {
-    let canister_id_for_miner = miner.canister_id.clone();
-    print(format!("Miner {:?} is over", canister_id_for_miner.clone()));
+// The miners of `box_id` that take part in its draw; see `T::Miner::is_eligible`.
+fn get_eligible_miners(box_id: &T::BoxId) -> Vec<T::Miner> {
+    let now = api::time();
+    get_box_miners(box_id).into_iter().filter(|miner| miner.is_eligible(now)).collect()
+}
 
-    MINERS.with(|miners_ref| {
-        let mut miners = miners_ref.borrow_mut();
-        if let Some(miner) = miners.get_mut(&canister_id_for_miner) {
-            miner.is_end = true;
-        }
-    });
+// Miners opened before stakes were recorded count at the box's minimum.
+fn stake_of(miner: &T::Miner, config: &T::BoxConfig) -> Nat {
+    miner.stake.clone().unwrap_or(config.min_miner_stake.clone()).into()
+}
 
-    match call::<(), (Vec<u8>,)>(candid::Principal::from_text(miner.canister_id).unwrap(), "get_subaccount", ()).await {
-        Ok((sub_vec,)) => {
-            let result_sub = Some(sub_vec);                 
-
-            match get_balance(api::id(), result_sub.clone()).await {
-            Ok(balance64) => { 
-                let award = Nat::from(balance64);                       
-                let prize_pool = award.clone() * 25u32 / 100u32;
-                let for_box_creator = award.clone() * 65u32 / 100u32;
-                let admin_tax = award.clone() - prize_pool.clone() - for_box_creator.clone();   
-
-                let is_end = BOXES.with(|boxes_ref| {
-                    let boxes = boxes_ref.borrow();
-                    if let Some(box_id) = boxes.get(&canister_id_for_miner) {                                        
-                        box_id.is_end
-                    }
-                    else {
-                        true
-                    }
-                });         
-        
-                if is_end {
-                    match transfer(award - FEE, result_sub.clone(), api::id(), None).await {
-                        Ok(index) => {
-                            print(format!("WHOLE_admin_tax success: {:?}", index));
-                        },
-                        Err(e) => {
-                            print(format!("WHOLE_admin_tax failed: {:?}", e));
-                        }
-                    }
-                }   
-                else {                                                                                                                                          
-                    match transfer(admin_tax - FEE, result_sub.clone(), api::id(), None).await {
-                        Ok(index) => {
-                            print(format!("admin_tax success: {:?}", index));
-                        },
-                        Err(e) => {
-                            print(format!("admin_tax failed: {:?}", e));
-                        }
-                    }
-                    let owner_box = BOXES.with(|boxes| boxes.borrow().get(&miner.box_id).cloned()).unwrap();
-                    let owner_user = owner_box.user;
-                    match transfer(for_box_creator - FEE, result_sub.clone(), Principal::from_text(owner_user).unwrap(), None).await {
-                        Ok(index) => {
-                            print(format!("for_box_creator success: {:?}", index));
-                        },
-                        Err(e) => {
-                            print(format!("for_box_creator failed: {:?}", e));
-                        }
-                    }
-                                                
-                    match call::<(), (Vec<u8>,)>(candid::Principal::from_text(miner.box_id).unwrap(), "get_subaccount", ()).await {
-                        Ok((sub_vec,)) => {
-                            let sub = Some(sub_vec); 
-                            match transfer(prize_pool - FEE, result_sub.clone(), api::id(), sub).await {
-                                Ok(index) => {
-                                    print(format!("prize_pool success: {:?}", index));
-                                },
-                                Err(e) => {
-                                    print(format!("prize_pool failed: {:?}", e));
-                                }
-                            }
-                        }
-                        Err(e) => {print(format!("get_subaccount for prize_pool failed: {}", e.1));}
-                    }         
-                }
-            },
-            Err(e) => {print(format!("{}", e));}
+// Holds a place in `box_info` for an entry that is about to await the ledger,
+// so concurrent entries cannot take more places than `max_miners`.
+fn reserve_slot(box_info: &T::BoxInfo) -> Result<(), T::BackendError> {
+    let box_id = box_info.canister_id;
+    RESERVED_SLOTS.with(|slots| {
+        let mut slots = slots.borrow_mut();
+        let reserved = slots.get(&box_id).copied().unwrap_or(0);
+        if let Some(max_miners) = box_rules::config_of(box_info).max_miners {
+            if get_eligible_miners(&box_id).len() + reserved as usize >= max_miners as usize {
+                return Err(T::BackendError::BoxFull { max_miners })
             }
         }
-        Err(e) => {print(format!("get_subaccount for prize_pool failed: {}", e.1));}
-    } 
+        slots.insert(box_id, reserved + 1);
+        Ok(())
+    })
 }
 
-
-
-async fn box_end(box_info: T::BoxInfo) -> () {
-    let canister_id_for_lottery = box_info.canister_id.clone();
-    print(format!("Lottery {:?} is over", canister_id_for_lottery.clone()));
-    match call::<(), (Vec<u8>,)>(candid::Principal::from_text(box_info.canister_id).unwrap(), "get_subaccount", ()).await {
-        Ok((sub_vec,)) => {
-            let result_sub = Some(sub_vec);
-            BOXES.with(|boxes_ref| {
-            let mut boxes = boxes_ref.borrow_mut();
-            if let Some(box_i) = boxes.get_mut(&canister_id_for_lottery.clone()) {
-                box_i.is_end = true;
-            }
-            });
-            let winner = choose_random_miner(canister_id_for_lottery.clone()).await;
-            if winner.is_some()
-            {
-                match get_balance(api::id(), result_sub.clone()).await {
-                    Ok(balance64) => {                                        
-                        let balance_nat = Nat::from(balance64);                                    
-                        let prize: Nat = balance_nat - FEE;      
-                        match transfer(prize, result_sub.clone(), Principal::from_text(winner.unwrap().user).unwrap(), None).await {
-                            Ok(index) => {
-                                print(format!("Prize success: {:?}", index));
-                            },
-                            Err(e) => {
-                                print(format!("Prize failed: {:?}", e));
-                            }
-                        }
-                    },
-                    Err(e) => { print(format!("Prize get balance failed: {:?}", e)); }
-                    } 
-            }
-            else {
-                print(format!("NO miners in {:?} ", canister_id_for_lottery.clone()));
-                match get_balance(api::id(), result_sub.clone()).await {
-                    Ok(balance64) => {                                        
-                        let balance_nat = Nat::from(balance64);                                                            
-                        let prize: Nat = balance_nat - FEE;      
-                        match transfer(prize, result_sub.clone(), Principal::from_text(box_info.user).unwrap(), None).await {
-                            Ok(index) => {
-                                print(format!("return Prize success: {:?}", index));
-                            },
-                            Err(e) => {
-                                print(format!("return Prize failed: {:?}", e));
-                            }
-                        }
-                    },
-                    Err(e) => { print(format!("Prize get balance failed: {:?}", e)); }
-                } 
-            }
+// Gives the place back once the entry has become a miner or been refunded.
+fn release_slot(box_id: &T::BoxId) {
+    RESERVED_SLOTS.with(|slots| {
+        let mut slots = slots.borrow_mut();
+        match slots.get(box_id).copied() {
+            Some(reserved) if reserved > 1 => { slots.insert(*box_id, reserved - 1); }
+            _ => { slots.remove(box_id); }
         }
-        Err(e) => {print(format!("cant call get_subaccount ({}) : {}", canister_id_for_lottery, e.1));}
-    } 
-
+    });
 }
 
 #[ic_cdk::update]
-async fn create_miner(box_id: String, award: Nat) -> Result<String, String> {    
-    if(award < MIN_MINER_COST)
-    {
-        return Err(format!("Minimum cost: {:?} ICP", MIN_MINER_COST))
-    }
-    let maybe_user = get_user_by_princ(ic_cdk::caller().to_text());
-    if maybe_user.is_none() {
-        return Err("User not found".to_string())
-    }
-    match get_allowance(ic_cdk::caller()).await {
+async fn create_miner(box_id: T::BoxId, award: T::TokenAmount) -> Result<T::MinerId, T::BackendError> {
+    let box_info = validate_miner_entry(&box_id, &award)?;
+    reserve_slot(&box_info)?;
+    let result = open_miner(box_info, award).await;
+    release_slot(&box_id);
+    result
+}
+
+async fn open_miner(box_info: T::BoxInfo, award: T::TokenAmount) -> Result<T::MinerId, T::BackendError> {
+    let box_id = box_info.canister_id;
+    let box_ledger = tokens::box_ledger(&box_info);
+    let token = tokens::get(&box_ledger)
+        .ok_or(T::BackendError::TokenNotSupported { ledger: box_ledger })?;
+    let config = box_rules::config_of(&box_info);
+    let ledger = tokens::fresh_client(token.ledger_canister_id).await?;
+    match ledger.allowance(account(ic_cdk::caller(), None), account(api::id(), None)).await {
         Ok(balance) => { 
-            if(balance >= award.clone() + FEE)
+            if balance >= award.0.clone() + ledger.fee()
             {  
-                let index = SUB_INDEX.with(|i| {
-                    let mut i = i.borrow_mut();
-                    *i += 1;
-                    *i
-                });                        
-                let sub = create_subaccount(api::id(), index);
+                // The box may have ended while we waited on the ledger; its
+                // place is held by the reservation.
+                let box_info = validate_miner_entry(&box_id, &award)?;
+                let index = state::next_sub_index();
+                let sub = T::subaccount::derive(T::subaccount::SubaccountKind::Miner, index);
                 let result_sub: Option<Vec<u8>> = Some(sub.to_vec());       
-                match transfer_from(award.clone(), ic_cdk::caller(), result_sub.clone()).await {
-                    Ok(index) => {                              
-                        let new_canister_id = create_miner_canister().await;
-                        match call::<(Vec<u8>,), ()>(candid::Principal::from_text(new_canister_id.clone()).unwrap(), "init", (result_sub.clone().unwrap(),)).await {
-                            Ok(()) => {},
-                            Err(e) => return Err(format!("Init Call failed: {:?}", e)),
-                        }  
+                match transfer_from(&ledger, award.0.clone(), ic_cdk::caller(), result_sub.clone()).await {
+                    Ok(block_index) => {                              
+                        let new_canister_id = match install_node(MINER_NODE_WASM, sub.to_vec(), never_a_miner).await {
+                            Ok(canister_id) => T::MinerId(canister_id),
+                            Err(reason) => {
+                                journal::record(journal::NewEntry { box_id: Some(box_id), ..payment_entry(&ledger, T::JournalReason::EntryFee, award.clone(), ic_cdk::caller(), result_sub, block_index) });
+                                return Err(refund_payment(&ledger, award.0, sub.to_vec(), T::BackendError::CanisterCreation(reason), Some(box_id), ic_cdk::caller()).await)
+                            },
+                        };
+                        journal::record(journal::NewEntry {
+                            box_id: Some(box_id),
+                            miner_id: Some(new_canister_id),
+                            ..payment_entry(&ledger, T::JournalReason::EntryFee, award.clone(), ic_cdk::caller(), result_sub.clone(), block_index)
+                        });
                         let now = api::time();                                                                
                         let new_miner_info = T::Miner {
-                            user: ic_cdk::caller().to_text(),
-                            canister_id: new_canister_id.clone(),
-                            box_id: box_id.clone(),
+                            user: ic_cdk::caller(),
+                            canister_id: new_canister_id,
+                            box_id,
                             reg_date: now,
-                            end_date: now + (MINER_TIME * 1_000_000_000),
-                            is_end: false
+                            // Installing the node took time the entry check did not
+                            // see; the miner still may not outlive its box.
+                            end_date: (now + (config.miner_duration_secs * 1_000_000_000)).min(box_info.end_date),
+                            is_end: false,
+                            subaccount: result_sub,
+                            stake: Some(award.clone()),
+                            status: None,
                         };              
                                                   
-                        MINERS.with(|miners: &std::cell::RefCell<BTreeMap<String, T::Miner>>| {
-                            miners.borrow_mut().insert(new_canister_id.clone(), new_miner_info.clone());
+                        MINERS.with(|miners| {
+                            miners.borrow_mut().insert(new_canister_id, new_miner_info.clone());
                         });
                         BOX_MINER.with(|map| {
-                            map.borrow_mut().insert(new_canister_id.clone(), box_id.clone());
+                            map.borrow_mut().insert(new_canister_id, box_id);
                         });                        
-                        //---------TIMER
-                        print(format!("Starting miner timer {:?}", new_canister_id.clone()));
-                        
-                        ic_cdk_timers::set_timer(Duration::from_secs(MINER_TIME), move || {                        
-                            ic_cdk::spawn(miner_end(new_miner_info.clone()));
-                        });
-                        /*set_timer(Duration::from_secs(MINER_TIME), move || {                            
-                            ic_cdk::spawn(async move {
-                                miner_end(new_miner_info.clone()).await;                                                               
-                            });                                                    
-                        });*/                   
-                        //--------------
+                        scheduler::schedule(T::JobKind::MinerEnd(new_canister_id), new_miner_info.end_date);
                        
                         Ok(new_canister_id)
                         
@@ -529,200 +561,395 @@ async fn create_miner(box_id: String, award: Nat) -> Result<String, String> {
                 }
             }
             else {
-                Err("You have no enough ICP".to_string())
+                Err(T::BackendError::InsufficientAllowance { have: balance.into(), need: award + ledger.fee().into() })
             }
         }
-        Err(e) => Err(e)
+        Err(e) => Err(T::BackendError::LedgerCall(e))
     }   
 }
 
-#[ic_cdk::update]
-async fn create_box(award: Nat) -> Result<T::BoxWithCount, String> {        
-    if(award < MIN_BOX_COST)
-    {
-        return Err(format!("Minimum cost: {:?} ICP", MIN_BOX_COST))
+// Everything that must hold before a caller pays `award` for a miner in
+// `box_id`, except room in the box; `reserve_slot` checks that.
+fn validate_miner_entry(box_id: &T::BoxId, award: &T::TokenAmount) -> Result<T::BoxInfo, T::BackendError> {
+    let caller = ic_cdk::caller();
+    if get_user_by_princ(caller).is_none() {
+        return Err(T::BackendError::NotRegistered)
+    }
+    if BLOCKED.with(|blocked| blocked.borrow().contains_key(&caller)) {
+        return Err(T::BackendError::Blocked)
+    }
+    let box_info = state::get_box(box_id).ok_or(T::BackendError::BoxNotFound)?;
+    let now = api::time();
+    if box_info.is_end || box_info.end_date <= now {
+        return Err(T::BackendError::BoxEnded)
+    }
+    let config = box_rules::config_of(&box_info);
+    if now + (config.miner_duration_secs * 1_000_000_000) > box_info.end_date {
+        return Err(T::BackendError::MinerOutlivesBox { box_end_date: box_info.end_date })
+    }
+    if *award < config.min_miner_stake {
+        return Err(T::BackendError::BelowMinimum { min: config.min_miner_stake })
+    }
+    if let Some(max) = config.max_miner_stake {
+        if *award > max {
+            return Err(T::BackendError::AboveMaximum { max })
+        }
     }
-    let maybe_user = get_user_by_princ(ic_cdk::caller().to_text());
+    Ok(box_info)
+}
+
+#[ic_cdk::update]
+async fn create_box(award: T::TokenAmount, ledger: Option<Principal>, config: Option<T::BoxConfig>) -> Result<T::BoxWithCount, T::BackendError> {        
+    let (token, config) = check_box_request(&award, ledger, config)?;
+    let maybe_user = get_user_by_princ(ic_cdk::caller());
     if maybe_user.is_none() {
-        return Err("User not found".to_string())
+        return Err(T::BackendError::NotRegistered)
     }
-    match get_allowance(ic_cdk::caller()).await {
+    let ledger_id = token.ledger_canister_id;
+    let ledger = tokens::fresh_client(ledger_id).await?;
+    match ledger.allowance(account(ic_cdk::caller(), None), account(api::id(), None)).await {
         Ok(balance) => {            
-            if(balance >= award.clone() + FEE)
+            if balance >= award.0.clone() + ledger.fee()
             {                                                                                    
-                let index = SUB_INDEX.with(|i| {
-                    let mut i = i.borrow_mut();
-                    *i += 1;
-                    *i
-                });                        
-                let sub = create_subaccount(api::id(), index);
-                let result_sub: Option<Vec<u8>> = Some(sub.to_vec());                                
-                match transfer_from(award, ic_cdk::caller(), result_sub.clone()).await {
-                    Ok(index) => {                              
-                        let new_canister_id = create_box_canister().await;
-                        match call::<(Vec<u8>,), ()>(candid::Principal::from_text(new_canister_id.clone()).unwrap(), "init", (result_sub.clone().unwrap(),)).await {
-                            Ok(()) => {},
-                            Err(e) => return Err(format!("Init Call failed: {:?}", e)),
-                        }  
-                        let now = api::time();                                                                
-                        let new_box_info = T::BoxInfo {
-                            user: ic_cdk::caller().to_text(),
-                            canister_id: new_canister_id.clone(),
-                            reg_date: now,
-                            end_date: now + (LOTTERY_TIME * 1_000_000_000),
-                            is_end: false
-                        };    
-                        BOXES.with(|boxes: &std::cell::RefCell<BTreeMap<String, T::BoxInfo>>| {
-                            boxes.borrow_mut().insert(new_canister_id.clone(), new_box_info.clone());
-                        });                        
-                        //---------TIMER                        
-                        print(format!("Starting lottery timer {:?}", new_canister_id.clone()));
-                        let box_for_timer = new_box_info.clone();
-                        ic_cdk_timers::set_timer(Duration::from_secs(LOTTERY_TIME), move || {                        
-                            ic_cdk::spawn(box_end(box_for_timer.clone()));
-                        });              
-                        //--------------
-                        let maybe_username = get_user_by_princ(new_box_info.clone().user);
-                        let username: String = match maybe_username {
-                            Some(user) => user.nickname,
-                            None => "Unknown".to_string(),
-                        };
-                        let answer = T::BoxWithCount {
-                            username: username,
-                            miner_count: 0,
-                            end_date: new_box_info.clone().end_date,
-                            reg_date: new_box_info.clone().reg_date,
-                            canister_id: new_box_info.clone().canister_id,
-                            user_miners: Vec::new()
-                        };
-                        Ok(answer)
-                    },                            
-                    Err(e) => 
-                    {                        
-                        Err(e)
-                    }
-                }                                                                    
+                let new_box_info = open_box(&ledger, ic_cdk::caller(), award, config.clone(), &T::BoxFunding::Allowance, None).await?;
+                let maybe_username = get_user_by_princ(new_box_info.user);
+                let username: String = match maybe_username {
+                    Some(user) => user.nickname.as_str().to_string(),
+                    None => "Unknown".to_string(),
+                };
+                let answer = T::BoxWithCount {
+                    username,
+                    miner_count: 0,
+                    end_date: new_box_info.clone().end_date,
+                    reg_date: new_box_info.clone().reg_date,
+                    canister_id: new_box_info.canister_id,
+                    user_miners: Vec::new(),
+                    ledger_canister_id: ledger_id,
+                    token_symbol: token.symbol.clone(),
+                    token_decimals: token.decimals,
+                    token_fee: token.fee.clone(),
+                    config,
+                    seed_commit: new_box_info.seed_commit.clone(),
+                    active_miner_count: 0,
+                    total_stake: Nat::from(0u32).into(),
+                };
+                Ok(answer)
             }
             else {
-               Err("You have no enough ICP".to_string()) 
+               Err(T::BackendError::InsufficientAllowance { have: balance.into(), need: award + ledger.fee().into() }) 
             }
         },
-        Err(e) => Err(e),
+        Err(e) => Err(T::BackendError::LedgerCall(e)),
     }
 }
 
-async fn choose_random_miner(box_id: String) -> Option<T::Miner> {
-    let filtered_miners = get_active_miners(box_id);
-    if filtered_miners.is_empty() {
-        return None;
+// The checks every new box goes through, whoever opens it. Returns the
+// box's token and its config, the default one if none was given.
+fn check_box_request(award: &T::TokenAmount, ledger: Option<Principal>, config: Option<T::BoxConfig>) -> Result<(T::TokenInfo, T::BoxConfig), T::BackendError> {
+    let ledger_id = ledger.unwrap_or_else(|| state::config().ledger_canister_id);
+    let token = match tokens::get(&ledger_id) {
+        Some(token) if token.enabled => token,
+        _ => return Err(T::BackendError::TokenNotSupported { ledger: ledger_id }),
+    };
+    let config = config.unwrap_or_else(|| box_rules::default_config(&token));
+    box_rules::validate(&config, &state::box_bounds(), &tokens::scale_from_e8s(MIN_MINER_COST, &token))?;
+    let min_cost = T::TokenAmount(tokens::scale_from_e8s(MIN_BOX_COST, &token));
+    if *award < min_cost {
+        return Err(T::BackendError::BelowMinimum { min: min_cost })
     }
+    Ok((token, config))
+}
 
-    // Get 32 bytes of randomness from the IC
-    let (random_bytes,): (Vec<u8>,) = raw_rand().await.unwrap();
-
-    // Convert the first 8 bytes to a u64
-    let rand_num = u64::from_le_bytes(random_bytes[0..8].try_into().unwrap());
-    let idx = (rand_num as usize) % filtered_miners.len();
-
-    Some(filtered_miners[idx].clone())
+// Pays `award` into a new box subaccount, installs the box canister and
+// schedules its end. A failed install sends the payment back.
+async fn open_box<L: Ledger>(ledger: &L, owner: Principal, award: T::TokenAmount, config: T::BoxConfig, funding: &T::BoxFunding, template_id: Option<u64>) -> Result<T::BoxInfo, T::BackendError> {
+    // Committed to before anyone can join, revealed when the box closes.
+    let committed_seed = match config.randomness {
+        Some(T::RandomnessMode::CommitReveal) => match raw_rand().await {
+            Ok((seed,)) => Some(seed),
+            Err(e) => return Err(T::BackendError::CanisterCreation(format!("raw_rand failed: {:?}", e))),
+        },
+        _ => None,
+    };
+    let index = state::next_sub_index();
+    let sub = T::subaccount::derive(T::subaccount::SubaccountKind::Box, index);
+    let result_sub: Option<Vec<u8>> = Some(sub.to_vec());
+    let (payer, block_index) = match funding {
+        T::BoxFunding::Allowance => (owner, transfer_from(ledger, award.0.clone(), owner, result_sub.clone()).await?),
+        T::BoxFunding::Treasury => (api::id(), transfer_from_treasury(ledger, award.0.clone(), sub.to_vec()).await?),
+    };
+    let new_canister_id = match install_node(BOX_NODE_WASM, sub.to_vec(), never_a_box).await {
+        Ok(canister_id) => T::BoxId(canister_id),
+        Err(reason) => {
+            journal::record(payment_entry(ledger, T::JournalReason::PrizePool, award.clone(), payer, result_sub, block_index));
+            return Err(refund_payment(ledger, award.0, sub.to_vec(), T::BackendError::CanisterCreation(reason), None, payer).await)
+        },
+    };
+    journal::record(journal::NewEntry {
+        box_id: Some(new_canister_id),
+        ..payment_entry(ledger, T::JournalReason::PrizePool, award, payer, result_sub.clone(), block_index)
+    });
+    let now = api::time();
+    let new_box_info = T::BoxInfo {
+        user: owner,
+        canister_id: new_canister_id,
+        reg_date: now,
+        end_date: now + (config.duration_secs * 1_000_000_000),
+        is_end: false,
+        subaccount: result_sub,
+        ledger: Some(ledger.canister_id()),
+        config: Some(config),
+        seed_commit: committed_seed.as_deref().map(T::draw::commit),
+        template_id,
+    };
+    if let Some(seed) = committed_seed {
+        state::put_committed_seed(new_canister_id, seed);
+    }
+    BOXES.with(|boxes| {
+        boxes.borrow_mut().insert(new_canister_id, new_box_info.clone());
+    });
+    scheduler::schedule(T::JobKind::BoxEnd(new_canister_id), new_box_info.end_date);
+    Ok(new_box_info)
 }
 
-async fn transfer(amount: Nat, from_sub: Option<Vec<u8>>, to: Principal, to_sub: Option<Vec<u8>>,) -> Result<Nat, String> {
-    let maybe_principal = candid::Principal::from_text(T::LEDGER_CANISTER);
-    match maybe_principal {
-        Ok(ledger_principal) => {        
-            let transfer_args = T::TransferArg {
-                memo: None,
-                amount: amount,
-                from_subaccount: from_sub,
-                fee: None,
-                to: T::ICRCAccount { owner: to, subaccount: to_sub },
-                created_at_time: None,
-            };
+// Controllers' templates are paid from the backend's own account, everyone
+// else's from their allowance, so only registered users may add one.
+#[ic_cdk::update]
+fn create_box_template(award: T::TokenAmount, ledger: Option<Principal>, config: Option<T::BoxConfig>) -> Result<T::BoxTemplate, T::BackendError> {
+    let owner = ic_cdk::caller();
+    let funding = if api::is_controller(&owner) {
+        T::BoxFunding::Treasury
+    } else if get_user_by_princ(owner).is_some() {
+        T::BoxFunding::Allowance
+    } else {
+        return Err(T::BackendError::NotRegistered)
+    };
+    if BLOCKED.with(|blocked| blocked.borrow().contains_key(&owner)) {
+        return Err(T::BackendError::Blocked)
+    }
+    let (token, config) = check_box_request(&award, ledger, config)?;
+    box_rules::validate_template(&config)?;
+    let template = T::BoxTemplate {
+        id: state::next_template_id(),
+        owner,
+        ledger: token.ledger_canister_id,
+        award,
+        config,
+        funding,
+        enabled: true,
+        current_box: None,
+        boxes_opened: 0,
+        last_error: None,
+        created_at: api::time(),
+    };
+    templates::put(template.clone());
+    templates::schedule_next(&template);
+    Ok(template)
+}
 
-            match call::<(T::TransferArg,), (T::TranferResult,)>(ledger_principal, "icrc1_transfer", (transfer_args,)).await {
-                Ok((result,)) => {
-                    match result {
-                        T::TranferResult::Ok(index) => {
-                            Ok(index)
-                        },
-                        T::TranferResult::Err(e) => {
-                            let json_str = serde_json::to_string(&e);
-                            match json_str {
-                                Ok(str) => {
-                                    Err("tranfer error: ".to_string() + &str)
-                                },
-                                Err(e) => {
-                                    Err("Unkown tranfer error".to_string())
-                                },
-                            }                            
-                        }
-                    }
-                },
-                Err(e) => Err(format!("icrc1_transfer Call failed: {:?}", e)),
-            }
-        }
-        Err(err) => {
-            Err("Bad ledger canister".to_string())
-        }
+/// Turning a template off lets its running box finish but opens no more.
+#[ic_cdk::update]
+fn set_box_template_enabled(id: u64, enabled: bool) -> Result<(), T::BackendError> {
+    let mut template = templates::get(id).ok_or(T::BackendError::TemplateNotFound)?;
+    if template.owner != ic_cdk::caller() {
+        require_controller()?;
     }
+    let restart = enabled && template.current_box.is_none();
+    template.enabled = enabled;
+    template.last_error = None;
+    if restart {
+        templates::schedule_next(&template);
+    }
+    templates::put(template);
+    Ok(())
 }
 
-async fn transfer_from(amount: Nat, from: Principal, to_sub: Option<Vec<u8>>) -> Result<Nat, String> {
-    let maybe_principal = candid::Principal::from_text(T::LEDGER_CANISTER);
-    match maybe_principal {
-        Ok(ledger_principal) => {
+#[ic_cdk::query]
+fn list_box_templates() -> Vec<T::BoxTemplate> {
+    templates::list()
+}
 
-            let transfer_from_args = T::ICRC2TransferFromArgs {
-                from: T::ICRCAccount { owner: from, subaccount: None },
-                memo: None,
-                amount: amount,
-                spender_subaccount: None,
-                fee: None,
-                to: T::ICRCAccount { owner: api::id(), subaccount: to_sub },
-                created_at_time: None,
-            };
+// The scheduler's way into `open_box`: the template is checked again, since
+// the bounds or its token may have changed since it was added.
+async fn open_template_box(template: &T::BoxTemplate) -> Result<T::BoxId, T::BackendError> {
+    let (_, config) = check_box_request(&template.award, Some(template.ledger), Some(template.config.clone()))?;
+    let ledger = tokens::fresh_client(template.ledger).await?;
+    let box_info = open_box(&ledger, template.owner, template.award.clone(), config, &template.funding, Some(template.id)).await?;
+    Ok(box_info.canister_id)
+}
 
-            match call::<(T::ICRC2TransferFromArgs,), (T::ICRC2TransferFromResult,)>(ledger_principal, "icrc2_transfer_from", (transfer_from_args,)).await {
-                Ok((approve_result,)) => {
-                    match approve_result {
-                        T::ICRC2TransferFromResult::Ok(index) => {
-                            Ok(index)
-                        },
-                        T::ICRC2TransferFromResult::Err(e) => {
-                            let json_str = serde_json::to_string(&e);
-                            match json_str {
-                                Ok(str) => {
-                                    Err("tranfer error: ".to_string() + &str)
-                                },
-                                Err(e) => {
-                                    Err("Unkown tranfer error".to_string())
-                                },
-                            }                            
-                        }
-                    }
-                },
-                Err(e) => Err(format!("icrc2_transfer_from Call failed: {:?}", e)),
-            }
-        }
-        Err(err) => {
-            Err("Bad ledger canister".to_string())
-        }
+// A payment from `payer`'s account into one of our subaccounts, not yet
+// tied to a box or miner.
+fn payment_entry<L: Ledger>(ledger: &L, reason: T::JournalReason, amount: T::TokenAmount, payer: Principal, to_sub: Option<Vec<u8>>, block_index: Nat) -> journal::NewEntry {
+    journal::NewEntry {
+        ledger: ledger.canister_id(),
+        reason,
+        amount,
+        from: T::ICRCAccount { owner: payer, subaccount: None },
+        to: T::ICRCAccount { owner: api::id(), subaccount: to_sub },
+        block_index,
+        box_id: None,
+        miner_id: None,
     }
 }
 
-pub fn create_subaccount(user_id: Principal, index: u32) -> [u8; 32] {
-    let mut subaccount: [u8; 32] = [0; 32];
-
-    subaccount[0] = u8::try_from(index).unwrap();
+// Draws up to `count` distinct winners, 1st place first, and keeps the
+// transcript so the draw can be checked with `T::draw::verify`. A box that
+// already drew keeps its recorded winners, so a retry cannot re-roll them.
+async fn choose_winners(box_info: &T::BoxInfo, count: usize) -> Result<Vec<T::Miner>, String> {
+    if let Some(transcript) = DRAWS.with(|draws| draws.borrow().get(&box_info.canister_id)) {
+        return transcript
+            .winners
+            .iter()
+            .map(|idx| {
+                let miner_id = transcript.candidates[*idx as usize].miner_id;
+                state::get_miner(&miner_id).ok_or(format!("Drawn miner {} is missing", miner_id))
+            })
+            .collect();
+    }
+    let filtered_miners = get_eligible_miners(&box_info.canister_id);
+    // A commit-reveal box still draws, over nobody, so its seed gets revealed.
+    if filtered_miners.is_empty() && box_info.seed_commit.is_none() {
+        return Ok(Vec::new());
+    }
+    let config = box_rules::config_of(box_info);
+    let mode = config.draw.clone().unwrap_or(T::DrawMode::Uniform);
+    let candidates: Vec<T::DrawCandidate> = filtered_miners
+        .iter()
+        .map(|miner| T::DrawCandidate {
+            miner_id: miner.canister_id,
+            stake: miner.stake.clone(),
+            weight: match mode {
+                T::DrawMode::StakeWeighted => stake_of(miner, &config),
+                T::DrawMode::Uniform => Nat::from(1u32),
+            },
+        })
+        .collect();
+    let weights = candidates
+        .iter()
+        .map(|candidate| T::draw::weight_of(&candidate.weight))
+        .collect::<Result<Vec<u128>, String>>()?;
+
+    // 32 bytes of randomness from the IC seed the draw. Commit-reveal boxes
+    // mix them with the seed committed to when the box opened.
+    let (random_bytes,): (Vec<u8>,) = raw_rand().await.map_err(|e| format!("raw_rand failed: {:?}", e))?;
+    let reveal = match &box_info.seed_commit {
+        Some(commit) => {
+            let committed_seed = state::committed_seed(&box_info.canister_id)
+                .ok_or(format!("Committed seed of {} is missing", box_info.canister_id))?;
+            Some(T::SeedReveal { commit: commit.clone(), committed_seed, close_seed: random_bytes.clone() })
+        }
+        None => None,
+    };
+    let seed: [u8; 32] = match &reveal {
+        Some(reveal) => T::draw::combine_seeds(&reveal.committed_seed, &reveal.close_seed),
+        None => random_bytes.try_into().map_err(|_| "raw_rand returned a short seed".to_string())?,
+    };
+    let winners = T::draw::run(seed, &weights, count);
+
+    let transcript = T::DrawTranscript {
+        box_id: box_info.canister_id,
+        algorithm_version: T::draw::ALGORITHM_VERSION,
+        seed: seed.to_vec(),
+        reveal,
+        mode,
+        candidates,
+        places: count as u32,
+        winners: winners.iter().map(|idx| *idx as u32).collect(),
+        drawn_at: api::time(),
+    };
+    DRAWS.with(|draws| draws.borrow_mut().insert(transcript.box_id, transcript));
 
-    let user_bytes = user_id.as_slice(); 
+    Ok(winners.into_iter().map(|idx| filtered_miners[idx].clone()).collect())
+}
 
-    for (i, byte) in user_bytes.iter().enumerate() {
-        if i + 1 < 32 {
-            subaccount[i + 1] = *byte;
-        }
+// Draws the jackpot winner by stake among the miners of the jackpot's token
+// that were running at some point since its period started. Returns the
+// winner with the id of the recorded transcript.
+async fn choose_jackpot_winner(jackpot: &T::Jackpot) -> Result<Option<(T::Miner, u64)>, String> {
+    let now = api::time();
+    let miners: Vec<(T::Miner, Nat)> = MINERS.with(|miners| {
+        miners
+            .borrow()
+            .iter()
+            .map(|(_, miner)| miner)
+            .filter(|miner| miner.end_date > jackpot.period_start && miner.is_eligible(now))
+            .filter_map(|miner| {
+                let box_info = state::get_box(&miner.box_id).filter(|box_info| tokens::box_ledger(box_info) == jackpot.ledger)?;
+                let stake = stake_of(&miner, &box_rules::config_of(&box_info));
+                Some((miner, stake))
+            })
+            .collect()
+    });
+    if miners.is_empty() {
+        return Ok(None);
     }
+    let candidates: Vec<T::DrawCandidate> = miners
+        .iter()
+        .map(|(miner, stake)| T::DrawCandidate {
+            miner_id: miner.canister_id,
+            stake: miner.stake.clone(),
+            weight: stake.clone(),
+        })
+        .collect();
+    let weights = candidates
+        .iter()
+        .map(|candidate| T::draw::weight_of(&candidate.weight))
+        .collect::<Result<Vec<u128>, String>>()?;
+
+    let (random_bytes,): (Vec<u8>,) = raw_rand().await.map_err(|e| format!("raw_rand failed: {:?}", e))?;
+    let seed: [u8; 32] = random_bytes.try_into().map_err(|_| "raw_rand returned a short seed".to_string())?;
+    let winner = match T::draw::run(seed, &weights, 1).into_iter().next() {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    let draw_id = jackpot::record_draw(T::JackpotDrawTranscript {
+        id: 0,
+        ledger: jackpot.ledger,
+        algorithm_version: T::draw::ALGORITHM_VERSION,
+        seed: seed.to_vec(),
+        period_start: jackpot.period_start,
+        candidates,
+        winner: winner as u32,
+        drawn_at: now,
+    });
+    Ok(Some((miners[winner].0.clone(), draw_id)))
+}
 
-    subaccount
-}
\ No newline at end of file
+// Moves `amount` from the backend's own account into one of its subaccounts.
+async fn transfer_from_treasury<L: Ledger>(ledger: &L, amount: Nat, to_sub: Vec<u8>) -> Result<Nat, T::BackendError> {
+    let (_, result) = tokens::with_fee_retry(ledger, |fee| {
+        ledger.transfer(T::TransferArg {
+            from_subaccount: None,
+            to: account(api::id(), Some(to_sub.clone())),
+            amount: amount.clone(),
+            fee: Some(fee),
+            memo: None,
+            created_at_time: None,
+        })
+    })
+    .await;
+    result.map_err(|failure| match failure {
+        LedgerFailure::Rejected(e) => T::BackendError::Ledger(e),
+        LedgerFailure::Call(e) => T::BackendError::LedgerCall(e),
+    })
+}
+
+async fn transfer_from<L: Ledger>(ledger: &L, amount: Nat, from: Principal, to_sub: Option<Vec<u8>>) -> Result<Nat, T::BackendError> {
+    let (fee, result) = tokens::with_fee_retry(ledger, |fee| {
+        ledger.transfer_from(T::ICRC2TransferFromArgs {
+            from: account(from, None),
+            memo: None,
+            amount: amount.clone(),
+            spender_subaccount: None,
+            fee: Some(fee),
+            to: account(api::id(), to_sub.clone()),
+            created_at_time: None,
+        })
+    })
+    .await;
+    result.map_err(|failure| match failure {
+        LedgerFailure::Rejected(e) => T::BackendError::from_transfer_from(e, (amount + fee).into()),
+        LedgerFailure::Call(e) => T::BackendError::LedgerCall(e),
+    })
+}
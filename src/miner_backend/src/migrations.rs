@@ -0,0 +1,422 @@
+use crate::jackpot;
+use crate::journal;
+use crate::state::{
+    self, USERS, BOXES, MINERS, BOX_MINER, SUB_INDEX, JOBS, SETTLEMENTS, JOURNAL, TOKENS, BLOCKED,
+    BOX_RESULTS, DRAWS,
+};
+use candid::Principal;
+use ic_cdk::api::{self, print};
+use types::{self as T};
+
+/// Brings stable memory up to `T::STATE_VERSION`, one step at a time.
+pub fn run() {
+    // The legacy blob has no envelope; read it before the memory manager is touched.
+    let legacy: Option<T::legacy::StateV1> = state::has_legacy_layout()
+        .then(|| ic_cdk::storage::stable_restore().expect("Failed to decode v1 state"));
+    let version = if legacy.is_some() {
+        1
+    } else {
+        state::state_version()
+    };
+    migrate(version, legacy, api::time());
+}
+
+// The steps from `version` up; `legacy` is the v1 blob when starting at 1.
+fn migrate(mut version: u32, mut legacy: Option<T::legacy::StateV1>, now: u64) {
+    while version < T::STATE_VERSION {
+        log(format!("Migrating state v{} -> v{}", version, version + 1));
+        match version {
+            1 => migrate_v1_to_v2(legacy.take().expect("No v1 state to migrate")),
+            2 => migrate_v2_to_v3(),
+            3 => migrate_v3_to_v4(),
+            4 => migrate_v4_to_v5(),
+            5 => migrate_v5_to_v6(),
+            6 => migrate_v6_to_v7(),
+            7 => migrate_v7_to_v8(),
+            _ => ic_cdk::trap(&format!("No migration from state version {}", version)),
+        }
+        version += 1;
+        state::set_state_version(version, now);
+    }
+    if version > T::STATE_VERSION {
+        ic_cdk::trap(&format!("State version {} is newer than this build ({})", version, T::STATE_VERSION));
+    }
+}
+
+// v1 -> v2: unpack the `stable_save` tuple into the stable maps.
+fn migrate_v1_to_v2((users, boxes, miners, box_miner, sub_index): T::legacy::StateV1) {
+
+    let mut u = state::users_v4();
+    for (id, user) in users {
+        u.insert(id, user);
+    }
+    let mut b = state::boxes_v4();
+    for (id, box_info) in boxes {
+        b.insert(id, box_info.into());
+    }
+    let mut m = state::miners_v4();
+    for (id, miner) in miners {
+        m.insert(id, miner.into());
+    }
+    let mut bm = state::box_miner_v4();
+    for (miner_id, box_id) in box_miner {
+        bm.insert(miner_id, box_id);
+    }
+    state::sub_index_v2().set(sub_index).expect("Failed to restore SUB_INDEX");
+}
+
+// v2 -> v3: the subaccount counter moves to a u64 cell. Records gain an
+// optional `subaccount`, which candid decodes as None for existing ones, so
+// old boxes and miners keep resolving theirs through their node canister.
+fn migrate_v2_to_v3() {
+    let sub_index = *state::sub_index_v2().get();
+    SUB_INDEX.with(|si| si.borrow_mut().set(sub_index as u64).expect("Failed to migrate SUB_INDEX"));
+}
+
+// v3 -> v4: boxes gain a `ledger`. Every box opened so far was paid in the
+// configured ledger, so pin them to it before upgrade args can change it.
+fn migrate_v3_to_v4() {
+    let ledger = state::config().ledger_canister_id;
+    let mut b = state::boxes_v4();
+    let ids: Vec<String> = b.iter().map(|(id, _)| id).collect();
+    for id in ids {
+        if let Some(mut box_info) = b.get(&id) {
+            box_info.ledger = Some(ledger);
+            b.insert(id, box_info);
+        }
+    }
+}
+
+// v4 -> v5: principals stored as text become `Principal`, `T::BoxId` and
+// `T::MinerId`. Every store whose keys or records changed is copied into a
+// new memory and the v4 store is emptied as soon as it is copied, so the
+// old records do not stay live next to the new ones. Text that does not
+// parse was never written by this canister, so such records are dropped
+// and logged rather than trapping the upgrade.
+fn migrate_v4_to_v5() {
+    let mut old_users = state::users_v4();
+    USERS.with(|u| {
+        let mut u = u.borrow_mut();
+        for (id, user) in old_users.iter() {
+            match Principal::from_text(&id) {
+                Ok(principal) => {
+                    if user.nickname.chars().count() > T::MAX_NICKNAME_LEN {
+                        log(format!("Cutting the nickname of user {} to {} characters", id, T::MAX_NICKNAME_LEN));
+                    }
+                    u.insert(principal, user.into());
+                }
+                Err(e) => skip("user", &id, e.to_string()),
+            }
+        }
+    });
+    old_users.clear_new();
+    let mut old_boxes = state::boxes_v4();
+    BOXES.with(|b| {
+        let mut b = b.borrow_mut();
+        for (id, box_info) in old_boxes.iter() {
+            match T::BoxInfo::try_from(box_info) {
+                Ok(box_info) => { b.insert(box_info.canister_id, box_info); }
+                Err(e) => skip("box", &id, e),
+            }
+        }
+    });
+    old_boxes.clear_new();
+    let mut old_miners = state::miners_v4();
+    MINERS.with(|m| {
+        let mut m = m.borrow_mut();
+        for (id, miner) in old_miners.iter() {
+            match T::Miner::try_from(miner) {
+                Ok(miner) => { m.insert(miner.canister_id, miner); }
+                Err(e) => skip("miner", &id, e),
+            }
+        }
+    });
+    old_miners.clear_new();
+    let mut old_box_miner = state::box_miner_v4();
+    BOX_MINER.with(|bm| {
+        let mut bm = bm.borrow_mut();
+        for (miner_id, box_id) in old_box_miner.iter() {
+            match (T::MinerId::from_text(&miner_id), T::BoxId::from_text(&box_id)) {
+                (Ok(miner_id), Ok(box_id)) => { bm.insert(miner_id, box_id); }
+                (Err(e), _) | (_, Err(e)) => skip("box miner", &miner_id, e),
+            }
+        }
+    });
+    old_box_miner.clear_new();
+    let mut old_jobs = state::jobs_v4();
+    let mut j = state::jobs_v5();
+    for (id, job) in old_jobs.iter() {
+        match T::legacy::JobV5::try_from(job) {
+            Ok(job) => { j.insert(id, job); }
+            Err(e) => skip("job", &id.to_string(), e),
+        }
+    }
+    old_jobs.clear_new();
+    let mut old_settlements = state::settlements_v4();
+    let mut s = state::settlements_v5();
+    for (id, settlement) in old_settlements.iter() {
+        match T::legacy::SettlementV5::try_from(settlement) {
+            Ok(settlement) => { s.insert(settlement.target, settlement); }
+            Err(e) => skip("settlement", &id, e),
+        }
+    }
+    old_settlements.clear_new();
+    // Entries keep their ids, so a bad one keeps its place and loses only
+    // the box and miner that did not parse.
+    JOURNAL.with(|j| {
+        let j = j.borrow();
+        let old = state::journal_v4();
+        for entry in (0..old.len()).filter_map(|id| old.get(id)) {
+            let id = entry.id;
+            let entry = T::JournalEntry::try_from(entry.clone()).unwrap_or_else(|e| {
+                skip("box and miner of journal entry", &id.to_string(), e);
+                T::JournalEntry::try_from(T::legacy::JournalEntryV4 { box_id: None, miner_id: None, ..entry })
+                    .expect("Journal entry without principal text")
+            });
+            j.append(&entry).expect("Failed to migrate JOURNAL");
+        }
+    });
+    state::clear_journal_v4();
+    let mut old_tokens = state::tokens_v4();
+    TOKENS.with(|t| {
+        let mut t = t.borrow_mut();
+        for (_, token) in old_tokens.iter() {
+            t.insert(token.ledger_canister_id, token);
+        }
+    });
+    old_tokens.clear_new();
+    let mut old_blocked = state::blocked_v4();
+    BLOCKED.with(|bl| {
+        let mut bl = bl.borrow_mut();
+        for (id, blocked_at) in old_blocked.iter() {
+            match Principal::from_text(&id) {
+                Ok(principal) => { bl.insert(principal, blocked_at); }
+                Err(e) => skip("blocked user", &id, e.to_string()),
+            }
+        }
+    });
+    old_blocked.clear_new();
+    let mut old_spares = state::spare_canisters_v4();
+    for (id, _) in old_spares.iter() {
+        match Principal::from_text(&id) {
+            Ok(principal) => state::put_spare_canister(principal),
+            Err(e) => skip("spare canister", &id, e.to_string()),
+        }
+    }
+    old_spares.clear_new();
+    let mut old_results = state::box_results_v4();
+    BOX_RESULTS.with(|r| {
+        let mut r = r.borrow_mut();
+        for (id, result) in old_results.iter() {
+            match T::BoxResult::try_from(result) {
+                Ok(result) => { r.insert(result.box_id, result); }
+                Err(e) => skip("box result", &id, e),
+            }
+        }
+    });
+    old_results.clear_new();
+    let mut old_draws = state::draws_v4();
+    DRAWS.with(|d| {
+        let mut d = d.borrow_mut();
+        for (id, transcript) in old_draws.iter() {
+            match T::DrawTranscript::try_from(transcript) {
+                Ok(transcript) => { d.insert(transcript.box_id, transcript); }
+                Err(e) => skip("draw", &id, e),
+            }
+        }
+    });
+    old_draws.clear_new();
+    let mut old_seeds = state::committed_seeds_v4();
+    for (id, seed) in old_seeds.iter() {
+        match T::BoxId::from_text(&id) {
+            Ok(box_id) => state::put_committed_seed(box_id, seed),
+            Err(e) => skip("committed seed", &id, e),
+        }
+    }
+    old_seeds.clear_new();
+}
+
+// v5 -> v6: jobs and settlements fold their bare `target` principal into a
+// typed `T::JobKind`, and settlements are keyed by it. Both move to new
+// memories and the v5 maps are emptied.
+fn migrate_v5_to_v6() {
+    let mut old_jobs = state::jobs_v5();
+    JOBS.with(|j| {
+        let mut j = j.borrow_mut();
+        for (id, job) in old_jobs.iter() {
+            j.insert(id, job.into());
+        }
+    });
+    old_jobs.clear_new();
+    let mut old_settlements = state::settlements_v5();
+    SETTLEMENTS.with(|s| {
+        let mut s = s.borrow_mut();
+        for (_, settlement) in old_settlements.iter() {
+            let settlement: T::Settlement = settlement.into();
+            s.insert(settlement.kind, settlement);
+        }
+    });
+    old_settlements.clear_new();
+}
+
+// v6 -> v7: entries journaled so far get their per-user and per-box index
+// entries; new ones are indexed as they are recorded.
+fn migrate_v6_to_v7() {
+    journal::backfill_index();
+}
+
+// v7 -> v8: jackpot events recorded so far get their per-ledger index entries.
+fn migrate_v7_to_v8() {
+    jackpot::backfill_index();
+}
+
+fn skip(what: &str, id: &str, error: String) {
+    log(format!("Dropping {} {} while migrating: {}", what, id, error));
+}
+
+// Unit tests run off-chain, where there is no replica log to print to.
+fn log(message: String) {
+    if cfg!(not(test)) {
+        print(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{BOX_JOURNAL, JACKPOT_HISTORY, LEDGER_JACKPOT_HISTORY};
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 10])
+    }
+
+    fn text(byte: u8) -> String {
+        principal(byte).to_text()
+    }
+
+    fn decode<R: Storable>(bytes: &'static [u8]) -> R {
+        R::from_bytes(Cow::Borrowed(bytes))
+    }
+
+    // The box, miner and users every fixture describes, as the v2-v4 maps held them.
+    fn seed_registries(box_info: T::legacy::BoxInfoV4, miner: T::legacy::MinerV4) {
+        state::users_v4().insert(text(1), decode(include_bytes!("../types/tests/fixtures/user_v4.bin")));
+        state::boxes_v4().insert(text(10), box_info);
+        state::miners_v4().insert(text(20), miner);
+        state::box_miner_v4().insert(text(20), text(10));
+    }
+
+    fn assert_registries() {
+        assert_eq!(state::state_version(), T::STATE_VERSION);
+        let user = USERS.with(|u| u.borrow().get(&principal(1))).unwrap();
+        assert_eq!(user.nickname.as_str(), "alice");
+        let box_info = state::get_box(&T::BoxId(principal(10))).unwrap();
+        assert_eq!((box_info.user, box_info.end_date), (principal(1), 2_000));
+        // Boxes from before v4 were paid in the configured ledger.
+        assert_eq!(box_info.ledger, Some(state::config().ledger_canister_id));
+        let miner = state::get_miner(&T::MinerId(principal(20))).unwrap();
+        assert_eq!((miner.user, miner.box_id), (principal(2), T::BoxId(principal(10))));
+        assert_eq!(BOX_MINER.with(|bm| bm.borrow().get(&T::MinerId(principal(20)))), Some(T::BoxId(principal(10))));
+        assert_eq!(SUB_INDEX.with(|si| *si.borrow().get()), 2);
+        assert!(state::users_v4().is_empty() && state::boxes_v4().is_empty() && state::miners_v4().is_empty());
+    }
+
+    #[test]
+    fn migrates_from_v1() {
+        let legacy: T::legacy::StateV1 = candid::decode_args(include_bytes!("../types/tests/fixtures/state_v1.bin")).unwrap();
+        migrate(1, Some(legacy), 5);
+        assert_registries();
+        let user = USERS.with(|u| u.borrow().get(&principal(2))).unwrap();
+        assert_eq!(user.nickname.as_str().chars().count(), T::MAX_NICKNAME_LEN);
+        let miner = state::get_miner(&T::MinerId(principal(20))).unwrap();
+        assert!(miner.is_end && miner.subaccount.is_none());
+    }
+
+    #[test]
+    fn migrates_from_v2() {
+        seed_registries(
+            decode(include_bytes!("../types/tests/fixtures/box_info_v2.bin")),
+            decode(include_bytes!("../types/tests/fixtures/miner_v2.bin")),
+        );
+        state::sub_index_v2().set(2).unwrap();
+        migrate(2, None, 5);
+        assert_registries();
+        assert!(state::get_box(&T::BoxId(principal(10))).unwrap().subaccount.is_none());
+    }
+
+    #[test]
+    fn migrates_from_v3() {
+        seed_registries(
+            decode(include_bytes!("../types/tests/fixtures/box_info_v3.bin")),
+            decode(include_bytes!("../types/tests/fixtures/miner_v3.bin")),
+        );
+        SUB_INDEX.with(|si| si.borrow_mut().set(2).unwrap());
+        migrate(3, None, 5);
+        assert_registries();
+        assert_eq!(state::get_box(&T::BoxId(principal(10))).unwrap().subaccount, Some(vec![1; 32]));
+        assert_eq!(state::get_miner(&T::MinerId(principal(20))).unwrap().subaccount, Some(vec![2; 32]));
+    }
+
+    #[test]
+    fn migrates_from_v4() {
+        seed_registries(
+            decode(include_bytes!("../types/tests/fixtures/box_info_v4.bin")),
+            decode(include_bytes!("../types/tests/fixtures/miner_v4.bin")),
+        );
+        SUB_INDEX.with(|si| si.borrow_mut().set(2).unwrap());
+        state::jobs_v4().insert(7, decode(include_bytes!("../types/tests/fixtures/job_v4.bin")));
+        state::settlements_v4().insert(text(10), decode(include_bytes!("../types/tests/fixtures/settlement_v4.bin")));
+        let journal: T::legacy::JournalEntryV4 = decode(include_bytes!("../types/tests/fixtures/journal_entry_v4.bin"));
+        state::journal_v4().append(&journal).unwrap();
+        state::draws_v4().insert(text(10), decode(include_bytes!("../types/tests/fixtures/draw_transcript_v4.bin")));
+        state::box_results_v4().insert(text(10), decode(include_bytes!("../types/tests/fixtures/box_result_v4.bin")));
+        migrate(4, None, 5);
+        assert_registries();
+        let box_id = T::BoxId(principal(10));
+        assert_eq!(state::get_miner(&T::MinerId(principal(20))).unwrap().status, Some(T::MinerStatus::Settled));
+        assert_eq!(JOBS.with(|j| j.borrow().get(&7)).unwrap().kind, T::JobKind::BoxEnd(box_id));
+        assert_eq!(SETTLEMENTS.with(|s| s.borrow().get(&T::JobKind::BoxEnd(box_id))).unwrap().status, T::SettlementStatus::Settled);
+        let entry = JOURNAL.with(|j| j.borrow().get(0)).unwrap();
+        assert_eq!((entry.id, entry.box_id), (3, Some(box_id)));
+        assert_eq!(DRAWS.with(|d| d.borrow().get(&box_id)).unwrap().winners, vec![0]);
+        assert_eq!(BOX_RESULTS.with(|r| r.borrow().get(&box_id)).unwrap().winners.len(), 1);
+        assert!(state::jobs_v4().is_empty() && state::journal_v4().is_empty());
+        assert!(BOX_JOURNAL.with(|bj| bj.borrow().contains_key(&(box_id, 3))));
+    }
+
+    #[test]
+    fn migrates_from_v5() {
+        let job: T::legacy::JobV4 = decode(include_bytes!("../types/tests/fixtures/job_v4.bin"));
+        state::jobs_v5().insert(7, job.try_into().unwrap());
+        let settlement: T::legacy::SettlementV4 = decode(include_bytes!("../types/tests/fixtures/settlement_v4.bin"));
+        let settlement = T::legacy::SettlementV5::try_from(settlement).unwrap();
+        state::settlements_v5().insert(settlement.target, settlement);
+        migrate(5, None, 5);
+        assert_eq!(state::state_version(), T::STATE_VERSION);
+        let box_end = T::JobKind::BoxEnd(T::BoxId(principal(10)));
+        let job = JOBS.with(|j| j.borrow().get(&7)).unwrap();
+        assert_eq!((job.kind, job.due_at, job.attempts), (box_end, 2_000, 3));
+        assert_eq!(SETTLEMENTS.with(|s| s.borrow().get(&box_end)).unwrap().payouts.len(), 1);
+        assert!(state::jobs_v5().is_empty() && state::settlements_v5().is_empty());
+    }
+
+    #[test]
+    fn migrates_from_v7() {
+        let event = |ledger: u8| T::JackpotEvent {
+            ledger: principal(ledger),
+            kind: T::JackpotEventKind::Rollover { box_id: T::BoxId(principal(10)), amount: T::TokenAmount(candid::Nat::from(1u32)) },
+            timestamp: 0,
+        };
+        JACKPOT_HISTORY.with(|h| {
+            let h = h.borrow();
+            for ledger in [30, 31, 30] {
+                h.append(&event(ledger)).unwrap();
+            }
+        });
+        migrate(7, None, 5);
+        let ids: Vec<(Principal, u64)> = LEDGER_JACKPOT_HISTORY.with(|index| index.borrow().iter().map(|(key, _)| key).collect());
+        assert_eq!(ids, vec![(principal(30), 0), (principal(30), 2), (principal(31), 1)]);
+    }
+}
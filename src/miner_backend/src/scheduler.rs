@@ -0,0 +1,224 @@
+use crate::jackpot;
+use crate::settlement::{self, box_end, jackpot_draw, miner_end};
+use crate::state::{self, BOXES, JOBS, MINERS};
+use crate::templates;
+use crate::tokens;
+use ic_cdk::api::{self, print};
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use types::{self as T};
+
+// Failed jobs are retried with exponential backoff, capped at an hour.
+const MAX_ATTEMPTS: u32 = 10;
+const BASE_BACKOFF_SECS: u64 = 30;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+
+thread_local! {
+    // The one timer driving every job, armed for the earliest due one.
+    static TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // Jobs whose handler is still awaiting. Heap only: an upgrade waits for
+    // outstanding calls, so nothing is in flight once it starts.
+    static RUNNING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Why a job has to run again.
+pub enum JobError {
+    /// It waits on other jobs; it runs again after the base backoff
+    /// without using up an attempt.
+    NotReady(String),
+    Failed(String),
+}
+
+impl From<String> for JobError {
+    fn from(reason: String) -> Self {
+        JobError::Failed(reason)
+    }
+}
+
+pub fn schedule(kind: T::JobKind, due_at: u64) -> u64 {
+    let id = state::next_job_id();
+    print(format!("Scheduling {:?} (job {})", kind, id));
+    JOBS.with(|jobs| {
+        jobs.borrow_mut().insert(id, T::Job { id, kind, due_at, attempts: 0 });
+    });
+    arm();
+    id
+}
+
+/// Whether a job of `kind` is waiting or running.
+pub fn has_job(kind: &T::JobKind) -> bool {
+    JOBS.with(|jobs| jobs.borrow().iter().any(|(_, job)| job.kind == *kind))
+}
+
+pub fn pending_jobs() -> Vec<T::Job> {
+    let mut jobs: Vec<T::Job> = JOBS.with(|jobs| jobs.borrow().iter().map(|(_, job)| job).collect());
+    jobs.sort_by_key(|job| (job.due_at, job.id));
+    jobs
+}
+
+/// Gives every open miner, box and jackpot, and every template waiting for
+/// its next box, a job if it lacks one, so settlements scheduled before jobs
+/// were persisted are not lost.
+pub fn ensure_settlement_jobs() {
+    let scheduled: BTreeSet<T::JobKind> = JOBS.with(|jobs| {
+        jobs.borrow().iter().map(|(_, job)| job.kind).collect()
+    });
+    let open_miners: Vec<T::Miner> = MINERS.with(|m| {
+        m.borrow().iter().map(|(_, miner)| miner).filter(|miner| !miner.is_end).collect()
+    });
+    for miner in open_miners {
+        let kind = T::JobKind::MinerEnd(miner.canister_id);
+        if !scheduled.contains(&kind) {
+            schedule(kind, miner.end_date);
+        }
+    }
+    let open_boxes: Vec<T::BoxInfo> = BOXES.with(|b| {
+        b.borrow().iter().map(|(_, box_info)| box_info).filter(|box_info| !box_info.is_end).collect()
+    });
+    for box_info in open_boxes {
+        let kind = T::JobKind::BoxEnd(box_info.canister_id);
+        if !scheduled.contains(&kind) {
+            schedule(kind, box_info.end_date);
+        }
+    }
+    for jackpot in jackpot::list() {
+        let kind = T::JobKind::JackpotDraw(jackpot.ledger);
+        if !scheduled.contains(&kind) {
+            schedule(kind, jackpot.next_draw_at);
+        }
+    }
+    for template in templates::list() {
+        if template.enabled && template.current_box.is_none() {
+            templates::schedule_next(&template);
+        }
+    }
+}
+
+/// (Re)arms the global timer for the earliest job that is not running yet.
+pub fn arm() {
+    TIMER.with(|timer| {
+        if let Some(id) = timer.borrow_mut().take() {
+            clear_timer(id);
+        }
+    });
+    let next_due = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| !is_running(job.id))
+            .map(|job| job.due_at)
+            .min()
+    });
+    if let Some(due_at) = next_due {
+        let delay = Duration::from_nanos(due_at.saturating_sub(api::time()));
+        let id = set_timer(delay, run_due_jobs);
+        TIMER.with(|timer| *timer.borrow_mut() = Some(id));
+    }
+}
+
+fn is_running(id: u64) -> bool {
+    RUNNING.with(|running| running.borrow().contains(&id))
+}
+
+fn run_due_jobs() {
+    TIMER.with(|timer| *timer.borrow_mut() = None);
+    let now = api::time();
+    let mut due: Vec<T::Job> = JOBS.with(|jobs| {
+        jobs.borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| job.due_at <= now && !is_running(job.id))
+            .collect()
+    });
+    // Jobs due at the same time are spawned miners first, then boxes, then
+    // jackpots. This only orders their starts: the spawned jobs interleave at
+    // every await, so a box waits for its miners in `settlement::box_end`.
+    due.sort_by_key(|job| (job.due_at, rank(&job.kind), job.id));
+
+    for mut job in due {
+        job.attempts += 1;
+        JOBS.with(|jobs| jobs.borrow_mut().insert(job.id, job.clone()));
+        RUNNING.with(|running| running.borrow_mut().insert(job.id));
+        ic_cdk::spawn(async move {
+            let result = execute(&job).await;
+            RUNNING.with(|running| running.borrow_mut().remove(&job.id));
+            match result {
+                Ok(()) => {
+                    JOBS.with(|jobs| jobs.borrow_mut().remove(&job.id));
+                }
+                Err(JobError::NotReady(reason)) => {
+                    let due_at = api::time() + BASE_BACKOFF_SECS * 1_000_000_000;
+                    print(format!("Job {} not ready: {}", job.id, reason));
+                    JOBS.with(|jobs| jobs.borrow_mut().insert(job.id, T::Job { due_at, attempts: job.attempts - 1, ..job.clone() }));
+                }
+                Err(JobError::Failed(e)) if job.attempts < MAX_ATTEMPTS => {
+                    let due_at = api::time() + backoff(job.attempts).as_nanos() as u64;
+                    print(format!("Job {} attempt {} failed: {}", job.id, job.attempts, e));
+                    JOBS.with(|jobs| jobs.borrow_mut().insert(job.id, T::Job { due_at, ..job.clone() }));
+                }
+                Err(JobError::Failed(e)) => {
+                    print(format!("Job {} gave up after {} attempts: {}", job.id, job.attempts, e));
+                    match job.kind {
+                        T::JobKind::TemplateBox { template_id } => templates::give_up(template_id, e),
+                        kind => settlement::give_up(kind, e),
+                    }
+                    JOBS.with(|jobs| jobs.borrow_mut().remove(&job.id));
+                }
+            }
+            arm();
+        });
+    }
+    arm();
+}
+
+fn rank(kind: &T::JobKind) -> u8 {
+    match kind {
+        T::JobKind::MinerEnd(_) => 0,
+        T::JobKind::BoxEnd(_) => 1,
+        T::JobKind::JackpotDraw(_) => 2,
+        T::JobKind::TemplateBox { .. } => 3,
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let secs = BASE_BACKOFF_SECS.saturating_mul(1u64 << attempts.min(16));
+    Duration::from_secs(secs.min(MAX_BACKOFF_SECS))
+}
+
+async fn execute(job: &T::Job) -> Result<(), JobError> {
+    match job.kind {
+        T::JobKind::MinerEnd(miner_id) => match state::get_miner(&miner_id) {
+            Some(miner) => {
+                let ledger = tokens::client_for_box(&miner.box_id).await.map_err(|e| format!("{:?}", e))?;
+                miner_end(&ledger, miner).await.map_err(JobError::from)
+            }
+            None => {
+                print(format!("Job {}: miner {} not found", job.id, miner_id));
+                Ok(())
+            }
+        },
+        T::JobKind::BoxEnd(box_id) => match state::get_box(&box_id) {
+            Some(box_info) => {
+                let ledger = tokens::client_for_box(&box_info.canister_id).await.map_err(|e| format!("{:?}", e))?;
+                box_end(&ledger, box_info).await
+            }
+            None => {
+                print(format!("Job {}: box {} not found", job.id, box_id));
+                Ok(())
+            }
+        },
+        T::JobKind::JackpotDraw(ledger_id) => match jackpot::get(&ledger_id) {
+            Some(_) => {
+                let ledger = tokens::fresh_client(ledger_id).await.map_err(|e| format!("{:?}", e))?;
+                jackpot_draw(&ledger).await.map_err(JobError::from)
+            }
+            None => {
+                print(format!("Job {}: no jackpot in {}", job.id, ledger_id));
+                Ok(())
+            }
+        },
+        T::JobKind::TemplateBox { template_id } => templates::open_next(template_id).await.map_err(JobError::from),
+    }
+}
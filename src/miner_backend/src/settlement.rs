@@ -0,0 +1,752 @@
+use crate::box_rules;
+use crate::jackpot;
+use crate::journal;
+use crate::scheduler::JobError;
+use crate::templates;
+use crate::tokens;
+use crate::state::{self, BOXES, BOX_RESULTS, DRAWS, MINERS, SETTLEMENTS};
+use crate::{choose_jackpot_winner, choose_winners, get_box_miners, retire_node};
+use candid::{Nat, Principal};
+use ic_cdk::api::{self, call::call, print};
+use types::{self as T};
+use types::ledger::{ExpectedFee, Ledger, LedgerFailure};
+
+/// Settles an expired miner: pays the jackpot contribution out of its stake
+/// and splits the rest between the admin, the box creator and the box prize
+/// pool. Its node goes back to the spare pool once it is settled. Returns
+/// `Err` when it should be retried.
+pub async fn miner_end<L: Ledger>(ledger: &L, miner: T::Miner) -> Result<(), String> {
+    let miner_id = miner.canister_id;
+    let mut settlement = load_or_start(T::JobKind::MinerEnd(miner_id));
+    let settled_before = is_terminal(&settlement);
+    if !settled_before {
+        if settlement.status == T::SettlementStatus::Pending {
+            print(format!("Miner {} is over", miner_id));
+            settlement.payouts = plan_miner_payouts(ledger, &miner).await?;
+            settlement.status = T::SettlementStatus::PayoutsIssued;
+            save(&mut settlement);
+        }
+        issue_payouts(ledger, &mut settlement, Some(miner.box_id), Some(miner_id)).await?;
+    }
+    mark_miner_ended(&miner_id, &settlement);
+    // Only once: by a later run the canister may serve someone else.
+    if !settled_before && settlement.status == T::SettlementStatus::Settled {
+        retire_node(miner_id.0).await;
+    }
+    Ok(())
+}
+
+/// Settles an expired box: pays its pot to randomly drawn miners by its
+/// prize table, or back to the creator when nobody joined. The pot is only
+/// drawn once all of its miners are settled; its node is retired like a
+/// miner's. Returns `Err` when it should be retried.
+pub async fn box_end<L: Ledger>(ledger: &L, box_info: T::BoxInfo) -> Result<(), JobError> {
+    let box_id = box_info.canister_id;
+    let mut settlement = load_or_start(T::JobKind::BoxEnd(box_id));
+    let settled_before = is_terminal(&settlement);
+    if !settled_before {
+        if settlement.status == T::SettlementStatus::Pending {
+            if let Some(miner) = get_box_miners(&box_id).into_iter().find(|miner| !miner.is_end) {
+                return Err(JobError::NotReady(format!("Lottery {} waits for miner {}", box_id, miner.canister_id)));
+            }
+            print(format!("Lottery {} is over", box_id));
+            settlement.payouts = plan_box_payouts(ledger, &box_info).await?;
+            settlement.status = T::SettlementStatus::PayoutsIssued;
+            save(&mut settlement);
+        }
+        issue_payouts(ledger, &mut settlement, Some(box_id), None).await?;
+    }
+    mark_box_ended(&box_id);
+    if !settled_before && settlement.status == T::SettlementStatus::Settled {
+        retire_node(box_id.0).await;
+    }
+    Ok(())
+}
+
+/// Draws the jackpot of `ledger`'s token among the miners that ran since the
+/// last draw, pays it out and schedules the next draw. Returns `Err` when it should be retried.
+pub async fn jackpot_draw<L: Ledger>(ledger: &L) -> Result<(), String> {
+    let ledger_id = ledger.canister_id();
+    let mut settlement = load_or_start(T::JobKind::JackpotDraw(ledger_id));
+    if !is_terminal(&settlement) {
+        if settlement.status == T::SettlementStatus::Pending {
+            print(format!("Jackpot draw of {}", ledger_id));
+            settlement.payouts = plan_jackpot_payouts(ledger).await?;
+            settlement.status = T::SettlementStatus::PayoutsIssued;
+            save(&mut settlement);
+        }
+        let winner = jackpot::get(&ledger_id).and_then(|jackpot| jackpot.pending_winner);
+        issue_payouts(ledger, &mut settlement, None, winner.map(|winner| winner.miner_id)).await?;
+    }
+    close_jackpot_draw(ledger_id, &settlement);
+    Ok(())
+}
+
+/// Called by the scheduler once a settlement ran out of retries.
+pub fn give_up(kind: T::JobKind, reason: String) {
+    let mut settlement = load_or_start(kind);
+    settlement.status = T::SettlementStatus::Failed { reason };
+    save(&mut settlement);
+    match kind {
+        T::JobKind::MinerEnd(miner_id) => mark_miner_ended(&miner_id, &settlement),
+        T::JobKind::BoxEnd(box_id) => mark_box_ended(&box_id),
+        T::JobKind::JackpotDraw(ledger) => close_jackpot_draw(ledger, &settlement),
+        // Opening a template's box settles nothing; the scheduler hands it to `templates`.
+        T::JobKind::TemplateBox { .. } => {}
+    }
+}
+
+/// Puts a settlement that gave up back to work; the caller schedules its
+/// job. Failed payouts are sent again as new transfers.
+pub fn requeue(kind: T::JobKind) -> Result<(), T::BackendError> {
+    let mut settlement = get_settlement(&kind).ok_or(T::BackendError::SettlementNotFound)?;
+    if !matches!(settlement.status, T::SettlementStatus::Failed { .. }) {
+        return Err(T::BackendError::SettlementNotFailed);
+    }
+    let now = api::time();
+    for payout in settlement.payouts.iter_mut() {
+        // Nothing moved for these, so a new created_at_time cannot pay twice
+        // and keeps the ledger from turning the resend down as too old.
+        // Pending ones keep theirs: they may have gone through.
+        if matches!(payout.status, T::PayoutStatus::Failed { .. }) {
+            payout.status = T::PayoutStatus::Pending;
+            payout.created_at_time = now;
+        }
+    }
+    settlement.status = if settlement.payouts.is_empty() {
+        T::SettlementStatus::Pending
+    } else {
+        T::SettlementStatus::PayoutsIssued
+    };
+    save(&mut settlement);
+    Ok(())
+}
+
+pub fn get_settlement(kind: &T::JobKind) -> Option<T::Settlement> {
+    SETTLEMENTS.with(|settlements| settlements.borrow().get(kind))
+}
+
+/// Splits a miner's stake into (prize pool, box creator, admin tax) by the
+/// box's percentages. The admin gets the rest, so rounding dust goes there.
+pub fn split_miner_stake(award: &Nat, config: &T::BoxConfig) -> (Nat, Nat, Nat) {
+    let prize_pool = award.clone() * config.prize_pct as u32 / 100u32;
+    let for_box_creator = award.clone() * config.creator_pct as u32 / 100u32;
+    let admin_tax = award.clone() - prize_pool.clone() - for_box_creator.clone();
+    (prize_pool, for_box_creator, admin_tax)
+}
+
+async fn plan_miner_payouts<L: Ledger>(ledger: &L, miner: &T::Miner) -> Result<Vec<T::PayoutRecord>, String> {
+    let sub = resolve_subaccount(&miner.subaccount, miner.canister_id.0).await?;
+    let award = ledger.balance_of(T::ICRCAccount { owner: api::id(), subaccount: Some(sub.clone()) }).await?;
+    let owner_box = match state::get_box(&miner.box_id).filter(is_open) {
+        Some(owner_box) => {
+            let box_sub = resolve_subaccount(&owner_box.subaccount, owner_box.canister_id.0).await?;
+            Some((owner_box, box_sub))
+        }
+        None => None,
+    };
+
+    let plan = PayoutPlan::new(T::JobKind::MinerEnd(miner.canister_id), sub, ledger.fee(), api::id(), api::time());
+    let contribution_pct = state::jackpot_settings().contribution_pct.unwrap_or(0);
+    Ok(miner_payouts(plan, award, contribution_pct, owner_box.as_ref(), miner.user))
+}
+
+// A miner whose box is gone or has started paying out has no pot to pay
+// into, so its whole stake goes back to `user`. Otherwise the jackpot
+// contribution comes off the top and the rest is split by the box's
+// percentages.
+fn miner_payouts(
+    mut plan: PayoutPlan,
+    mut award: Nat,
+    contribution_pct: u8,
+    owner_box: Option<&(T::BoxInfo, Vec<u8>)>,
+    user: Principal,
+) -> Vec<T::PayoutRecord> {
+    let Some((owner_box, box_sub)) = owner_box else {
+        plan.push(T::PayoutPurpose::Refund, award, user, None);
+        return plan.payouts;
+    };
+    let contribution = award.clone() * contribution_pct as u32 / 100u32;
+    if contribution > 0u32 {
+        let jackpot_sub = T::subaccount::jackpot().to_vec();
+        if plan.push(T::PayoutPurpose::JackpotContribution, contribution.clone(), plan.owner, Some(jackpot_sub)).is_some() {
+            award -= contribution;
+        }
+    }
+    let (prize_pool, for_box_creator, admin_tax) = split_miner_stake(&award, &box_rules::config_of(owner_box));
+    plan.push(T::PayoutPurpose::AdminTax, admin_tax, plan.owner, None);
+    plan.push(T::PayoutPurpose::CreatorShare, for_box_creator, owner_box.user, None);
+    plan.push(T::PayoutPurpose::PrizePool, prize_pool, plan.owner, Some(box_sub.clone()));
+    plan.payouts
+}
+
+async fn plan_box_payouts<L: Ledger>(ledger: &L, box_info: &T::BoxInfo) -> Result<Vec<T::PayoutRecord>, String> {
+    let box_id = box_info.canister_id;
+    let sub = resolve_subaccount(&box_info.subaccount, box_id.0).await?;
+    let balance = ledger.balance_of(T::ICRCAccount { owner: api::id(), subaccount: Some(sub.clone()) }).await?;
+
+    let config = box_rules::config_of(box_info);
+    let table = box_rules::prize_table(&config);
+    let winners = choose_winners(box_info, box_rules::places(&table)).await?;
+
+    let mut plan = PayoutPlan::new(T::JobKind::BoxEnd(box_id), sub, ledger.fee(), api::id(), api::time());
+    let mut result = T::BoxResult {
+        box_id,
+        winners: Vec::new(),
+        refund: None,
+        decided_at: api::time(),
+        reveal: DRAWS.with(|draws| draws.borrow().get(&box_id)).and_then(|draw| draw.reveal),
+        rollover: None,
+    };
+    if winners.is_empty() {
+        print(format!("NO miners in {} ", box_id));
+        let mut refund = balance.clone();
+        let rollover = balance * config.rollover_pct.unwrap_or(0) as u32 / 100u32;
+        if rollover > 0u32 {
+            let jackpot_sub = T::subaccount::jackpot().to_vec();
+            result.rollover = plan.push(T::PayoutPurpose::Rollover, rollover.clone(), plan.owner, Some(jackpot_sub)).map(T::TokenAmount);
+            if result.rollover.is_some() {
+                refund -= rollover;
+            }
+        }
+        result.refund = plan.push(T::PayoutPurpose::Refund, refund, box_info.user, None).map(T::TokenAmount);
+    }
+    else {
+        let prizes = box_rules::split_prizes(&table, &balance, winners.len());
+        for (place, (winner, prize)) in winners.into_iter().zip(prizes).enumerate() {
+            let amount = plan.push(T::PayoutPurpose::Prize, prize, winner.user, None);
+            result.winners.push(T::BoxWinner {
+                place: place as u32 + 1,
+                miner_id: winner.canister_id,
+                user: winner.user,
+                amount: T::TokenAmount(amount.unwrap_or_else(|| Nat::from(0u32))),
+            });
+        }
+    }
+    BOX_RESULTS.with(|results| results.borrow_mut().insert(result.box_id, result));
+    Ok(plan.payouts)
+}
+
+// The whole jackpot goes to one miner drawn by stake among those that ran
+// since the last draw. With nobody to draw it carries over to the next period.
+async fn plan_jackpot_payouts<L: Ledger>(ledger: &L) -> Result<Vec<T::PayoutRecord>, String> {
+    let ledger_id = ledger.canister_id();
+    let jackpot = jackpot::get(&ledger_id).ok_or(format!("No jackpot in {}", ledger_id))?;
+    let sub = T::subaccount::jackpot().to_vec();
+    let balance = ledger.balance_of(T::ICRCAccount { owner: api::id(), subaccount: Some(sub.clone()) }).await?;
+    let drawn = choose_jackpot_winner(&jackpot).await?;
+
+    // Re-read: rollovers may have been booked while the calls were out.
+    let mut jackpot = jackpot::get(&ledger_id).unwrap_or(jackpot);
+    jackpot.balance = balance.clone().into();
+    let mut plan = PayoutPlan::new(T::JobKind::JackpotDraw(ledger_id), sub, ledger.fee(), api::id(), api::time());
+    if let Some((winner, draw_id)) = drawn {
+        if let Some(amount) = plan.push(T::PayoutPurpose::JackpotPrize, balance, winner.user, None) {
+            jackpot.pending_winner = Some(T::BoxWinner {
+                place: 1,
+                miner_id: winner.canister_id,
+                user: winner.user,
+                amount: T::TokenAmount(amount),
+            });
+            jackpot.pending_draw = Some(draw_id);
+        }
+    }
+    jackpot::put(jackpot);
+    Ok(plan.payouts)
+}
+
+// Sends every payout that is still pending. Transient ledger errors abort
+// with `Err` so the job is retried; the ledger dedups the resent transfers.
+// Each completed payout is journaled in the same step that marks it Done.
+async fn issue_payouts<L: Ledger>(ledger: &L, settlement: &mut T::Settlement, box_id: Option<T::BoxId>, miner_id: Option<T::MinerId>) -> Result<(), String> {
+    for i in 0..settlement.payouts.len() {
+        if settlement.payouts[i].status != T::PayoutStatus::Pending {
+            continue;
+        }
+        let payout = settlement.payouts[i].clone();
+        match send(ledger, &payout).await {
+            Sent::Done(block_index) => {
+                print(format!("{:?} success: {:?}", payout.purpose, block_index));
+                let amount = payout.amount.clone();
+                match (&payout.purpose, box_id, miner_id) {
+                    (T::PayoutPurpose::Rollover, Some(box_id), _) => {
+                        jackpot::credit(ledger.canister_id(), T::JackpotEventKind::Rollover { box_id, amount });
+                    }
+                    (T::PayoutPurpose::JackpotContribution, _, Some(miner_id)) => {
+                        jackpot::credit(ledger.canister_id(), T::JackpotEventKind::Contribution { miner_id, amount });
+                    }
+                    _ => {}
+                }
+                journal::record(journal::NewEntry {
+                    ledger: ledger.canister_id(),
+                    reason: payout.purpose.clone().into(),
+                    amount: payout.amount,
+                    from: T::ICRCAccount { owner: api::id(), subaccount: Some(payout.from_subaccount) },
+                    to: payout.to,
+                    block_index: block_index.clone(),
+                    box_id,
+                    miner_id,
+                });
+                settlement.payouts[i].status = T::PayoutStatus::Done { block_index };
+            }
+            Sent::Retry(reason) => {
+                save(settlement);
+                return Err(format!("{:?} failed: {}", payout.purpose, reason));
+            }
+            Sent::BadFee(fee) => {
+                tokens::set_fee(ledger.canister_id(), fee.clone());
+                reprice_pending(settlement, &fee);
+                save(settlement);
+                return Err(format!("{:?} failed: ledger fee is now {}", payout.purpose, fee));
+            }
+            Sent::Failed(reason) => {
+                print(format!("{:?} failed: {}", payout.purpose, reason));
+                settlement.payouts[i].status = T::PayoutStatus::Failed { reason };
+            }
+        }
+        save(settlement);
+    }
+
+    settlement.status = final_status(&settlement.payouts);
+    save(settlement);
+    Ok(())
+}
+
+// How a single payout went.
+#[derive(Debug, PartialEq)]
+enum Sent {
+    /// It is in the ledger at this block, sent now or by an earlier attempt.
+    Done(Nat),
+    /// Nothing is known to have moved; resend it as is.
+    Retry(String),
+    /// Nothing moved because the fee changed; re-price and resend.
+    BadFee(Nat),
+    /// The ledger turned it down for good.
+    Failed(String),
+}
+
+// Sends one payout with its stored memo and created_at_time, so a resend of
+// a transfer that already went through comes back as a duplicate.
+async fn send<L: Ledger>(ledger: &L, payout: &T::PayoutRecord) -> Sent {
+    let args = T::TransferArg {
+        from_subaccount: Some(payout.from_subaccount.clone()),
+        to: payout.to.clone(),
+        amount: payout.amount.0.clone(),
+        fee: payout.fee.clone(),
+        memo: Some(payout.memo.clone()),
+        created_at_time: Some(payout.created_at_time),
+    };
+    match ledger.transfer(args).await {
+        Ok(block_index)
+        | Err(LedgerFailure::Rejected(T::TransferError::Duplicate { duplicate_of: block_index })) => Sent::Done(block_index),
+        Err(failure) if failure.is_retryable() => Sent::Retry(format!("{:?}", failure)),
+        Err(failure) => match failure.expected_fee() {
+            Some(fee) => Sent::BadFee(fee),
+            None => Sent::Failed(format!("{:?}", failure)),
+        },
+    }
+}
+
+// A settlement with every payout sent fails if any one of them failed.
+fn final_status(payouts: &[T::PayoutRecord]) -> T::SettlementStatus {
+    match payouts.iter().find_map(|p| match &p.status {
+        T::PayoutStatus::Failed { reason } => Some(reason.clone()),
+        _ => None,
+    }) {
+        Some(reason) => T::SettlementStatus::Failed { reason },
+        None => T::SettlementStatus::Settled,
+    }
+}
+
+// Nothing moves on BadFee, so every pending payout keeps its gross share and
+// is re-priced against the new fee. Shares that no longer cover it fail.
+fn reprice_pending(settlement: &mut T::Settlement, fee: &Nat) {
+    for payout in settlement.payouts.iter_mut() {
+        let old_fee = match (&payout.status, &payout.fee) {
+            (T::PayoutStatus::Pending, Some(old_fee)) => old_fee.clone(),
+            _ => continue,
+        };
+        let gross = payout.amount.0.clone() + old_fee;
+        if gross <= *fee {
+            payout.status = T::PayoutStatus::Failed { reason: format!("{} is below the fee of {}", gross, fee) };
+        } else {
+            payout.amount = (gross - fee.clone()).into();
+            payout.fee = Some(fee.clone());
+        }
+    }
+}
+
+struct PayoutPlan {
+    kind: T::JobKind,
+    from_subaccount: Vec<u8>,
+    fee: Nat,
+    /// This canister, which holds the admin account and every subaccount.
+    owner: Principal,
+    created_at_time: u64,
+    payouts: Vec<T::PayoutRecord>,
+}
+
+impl PayoutPlan {
+    fn new(kind: T::JobKind, from_subaccount: Vec<u8>, fee: Nat, owner: Principal, now: u64) -> Self {
+        PayoutPlan {
+            kind,
+            from_subaccount,
+            fee,
+            owner,
+            created_at_time: now,
+            payouts: Vec::new(),
+        }
+    }
+
+    // Amounts are gross; the ledger fee is taken off here and the net amount
+    // returned. Shares too small to cover the fee are left in the subaccount.
+    fn push(&mut self, purpose: T::PayoutPurpose, gross: Nat, to: Principal, to_sub: Option<Vec<u8>>) -> Option<Nat> {
+        if gross <= self.fee {
+            return None;
+        }
+        let memo = payout_memo(&self.kind, self.payouts.len());
+        let amount = gross - self.fee.clone();
+        self.payouts.push(T::PayoutRecord {
+            purpose,
+            from_subaccount: self.from_subaccount.clone(),
+            to: T::ICRCAccount { owner: to, subaccount: to_sub },
+            amount: amount.clone().into(),
+            fee: Some(self.fee.clone()),
+            memo,
+            created_at_time: self.created_at_time,
+            status: T::PayoutStatus::Pending,
+        });
+        Some(amount)
+    }
+}
+
+// kind tag + payout index + target principal bytes, at most 31 bytes.
+fn payout_memo(kind: &T::JobKind, index: usize) -> Vec<u8> {
+    let (tag, target) = match kind {
+        T::JobKind::MinerEnd(miner_id) => (b'M', miner_id.0.as_slice().to_vec()),
+        T::JobKind::BoxEnd(box_id) => (b'B', box_id.0.as_slice().to_vec()),
+        T::JobKind::JackpotDraw(ledger) => (b'J', ledger.as_slice().to_vec()),
+        T::JobKind::TemplateBox { template_id } => (b'T', template_id.to_be_bytes().to_vec()),
+    };
+    let mut memo = vec![tag, index as u8];
+    memo.extend_from_slice(&target);
+    memo
+}
+
+fn load_or_start(kind: T::JobKind) -> T::Settlement {
+    get_settlement(&kind).unwrap_or(T::Settlement {
+        kind,
+        status: T::SettlementStatus::Pending,
+        payouts: Vec::new(),
+        updated_at: api::time(),
+    })
+}
+
+// Only terminal settlements close their box or miner, so a half-paid one
+// keeps showing up until it is Settled or Failed.
+fn mark_miner_ended(miner_id: &T::MinerId, settlement: &T::Settlement) {
+    if let Some(mut miner) = state::get_miner(miner_id) {
+        miner.is_end = true;
+        miner.status = Some(ended_status(settlement));
+        MINERS.with(|miners| miners.borrow_mut().insert(*miner_id, miner));
+    }
+}
+
+// Refunded when the whole stake went back to the miner's owner, Failed when
+// it never reached the pot.
+fn ended_status(settlement: &T::Settlement) -> T::MinerStatus {
+    let refunded = !settlement.payouts.is_empty()
+        && settlement.payouts.iter().all(|payout| payout.purpose == T::PayoutPurpose::Refund);
+    match settlement.status {
+        T::SettlementStatus::Failed { .. } if refunded || !reached_pot(settlement) => T::MinerStatus::Failed,
+        _ if refunded => T::MinerStatus::Refunded,
+        _ => T::MinerStatus::Settled,
+    }
+}
+
+// A failed settlement still counts as paid in when its prize pool share
+// went through; only the admin or creator transfer failed then.
+fn reached_pot(settlement: &T::Settlement) -> bool {
+    match settlement.status {
+        T::SettlementStatus::Failed { .. } => settlement.payouts.iter().any(|payout| {
+            payout.purpose == T::PayoutPurpose::PrizePool && matches!(payout.status, T::PayoutStatus::Done { .. })
+        }),
+        _ => true,
+    }
+}
+
+fn mark_box_ended(box_id: &T::BoxId) {
+    if let Some(mut box_info) = state::get_box(box_id) {
+        box_info.is_end = true;
+        let template_id = box_info.template_id;
+        BOXES.with(|boxes| boxes.borrow_mut().insert(*box_id, box_info));
+        if let Some(template_id) = template_id {
+            templates::box_ended(template_id, *box_id);
+        }
+    }
+}
+
+// A jackpot draws again and again, so its settlement is cleared once the
+// draw is booked and the next one starts from Pending.
+fn close_jackpot_draw(ledger: Principal, settlement: &T::Settlement) {
+    SETTLEMENTS.with(|settlements| settlements.borrow_mut().remove(&T::JobKind::JackpotDraw(ledger)));
+    jackpot::close_draw(ledger, settlement);
+}
+
+// A box takes prize pool shares until its own settlement has been planned.
+fn is_open(box_info: &T::BoxInfo) -> bool {
+    !box_info.is_end
+        && get_settlement(&T::JobKind::BoxEnd(box_info.canister_id)).is_none_or(|settlement| settlement.status == T::SettlementStatus::Pending)
+}
+
+fn is_terminal(settlement: &T::Settlement) -> bool {
+    matches!(settlement.status, T::SettlementStatus::Settled | T::SettlementStatus::Failed { .. })
+}
+
+fn save(settlement: &mut T::Settlement) {
+    settlement.updated_at = api::time();
+    SETTLEMENTS.with(|settlements| {
+        settlements.borrow_mut().insert(settlement.kind, settlement.clone());
+    });
+}
+
+// Boxes and miners opened before state v3 only know their subaccount through
+// their node canister.
+async fn resolve_subaccount(stored: &Option<Vec<u8>>, canister_id: Principal) -> Result<Vec<u8>, String> {
+    match stored {
+        Some(sub) => Ok(sub.clone()),
+        None => node_subaccount(canister_id).await,
+    }
+}
+
+async fn node_subaccount(canister_id: Principal) -> Result<Vec<u8>, String> {
+    match call::<(), (Vec<u8>,)>(canister_id, "get_subaccount", ()).await {
+        Ok((sub_vec,)) => Ok(sub_vec),
+        Err(e) => Err(format!("cant call get_subaccount ({}) : {}", canister_id, e.1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use types::ledger::MockLedger;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte])
+    }
+
+    fn backend() -> Principal {
+        principal(1)
+    }
+
+    fn miner_sub() -> Vec<u8> {
+        T::subaccount::derive(T::subaccount::SubaccountKind::Miner, 7).to_vec()
+    }
+
+    fn plan(fee: u32) -> PayoutPlan {
+        PayoutPlan::new(T::JobKind::MinerEnd(T::MinerId(principal(2))), miner_sub(), Nat::from(fee), backend(), 1_000)
+    }
+
+    // A ledger holding `balance` in the miner's subaccount.
+    fn ledger(fee: u32, balance: u32) -> MockLedger {
+        let ledger = MockLedger::new(principal(3), backend(), Nat::from(fee));
+        ledger.mint(&T::ICRCAccount { owner: backend(), subaccount: Some(miner_sub()) }, Nat::from(balance));
+        ledger
+    }
+
+    fn settlement(payouts: Vec<T::PayoutRecord>) -> T::Settlement {
+        T::Settlement {
+            kind: T::JobKind::MinerEnd(T::MinerId(principal(2))),
+            status: T::SettlementStatus::PayoutsIssued,
+            payouts,
+            updated_at: 1_000,
+        }
+    }
+
+    fn config() -> T::BoxConfig {
+        T::BoxConfig {
+            duration_secs: 3_600,
+            miner_duration_secs: 600,
+            prize_pct: 25,
+            creator_pct: 65,
+            admin_pct: 10,
+            min_miner_stake: Nat::from(100u32).into(),
+            max_miner_stake: None,
+            max_miners: None,
+            draw: Some(T::DrawMode::StakeWeighted),
+            prize_table: None,
+            randomness: None,
+            rollover_pct: None,
+        }
+    }
+
+    fn open_box() -> (T::BoxInfo, Vec<u8>) {
+        let box_sub = T::subaccount::derive(T::subaccount::SubaccountKind::Box, 7).to_vec();
+        let box_info = T::BoxInfo {
+            user: principal(5),
+            canister_id: T::BoxId(principal(6)),
+            reg_date: 0,
+            end_date: 3_600,
+            is_end: false,
+            subaccount: Some(box_sub.clone()),
+            ledger: Some(principal(3)),
+            config: Some(config()),
+            seed_commit: None,
+            template_id: None,
+        };
+        (box_info, box_sub)
+    }
+
+    type Account = (Principal, Option<Vec<u8>>);
+
+    // (purpose, recipient, net amount) of each planned payout.
+    fn summary(payouts: &[T::PayoutRecord]) -> Vec<(T::PayoutPurpose, Account, Nat)> {
+        payouts.iter().map(|payout| (payout.purpose.clone(), account(payout.to.owner, payout.to.subaccount.clone()), payout.amount.0.clone())).collect()
+    }
+
+    fn account(owner: Principal, subaccount: Option<Vec<u8>>) -> Account {
+        (owner, subaccount)
+    }
+
+    #[test]
+    fn miner_stake_is_split_by_the_box_percentages() {
+        let (prize_pool, for_box_creator, admin_tax) = split_miner_stake(&Nat::from(1_000u32), &config());
+        assert_eq!((prize_pool, for_box_creator, admin_tax), (Nat::from(250u32), Nat::from(650u32), Nat::from(100u32)));
+    }
+
+    #[test]
+    fn rounding_dust_of_a_miner_stake_goes_to_the_admin() {
+        let (prize_pool, for_box_creator, admin_tax) = split_miner_stake(&Nat::from(999u32), &config());
+        assert_eq!((prize_pool, for_box_creator, admin_tax), (Nat::from(249u32), Nat::from(649u32), Nat::from(101u32)));
+    }
+
+    #[test]
+    fn miner_of_an_open_box_pays_into_its_pot() {
+        let owner_box = open_box();
+        let payouts = miner_payouts(plan(10), Nat::from(1_000u32), 0, Some(&owner_box), principal(4));
+        assert_eq!(
+            summary(&payouts),
+            vec![
+                (T::PayoutPurpose::AdminTax, account(backend(), None), Nat::from(90u32)),
+                (T::PayoutPurpose::CreatorShare, account(principal(5), None), Nat::from(640u32)),
+                (T::PayoutPurpose::PrizePool, account(backend(), Some(owner_box.1)), Nat::from(240u32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn miner_without_an_open_box_gets_its_stake_back() {
+        let payouts = miner_payouts(plan(10), Nat::from(1_000u32), 10, None, principal(4));
+        assert_eq!(summary(&payouts), vec![(T::PayoutPurpose::Refund, account(principal(4), None), Nat::from(990u32))]);
+
+        let mut refund = settlement(payouts);
+        assert_eq!(ended_status(&refund), T::MinerStatus::Refunded);
+        refund.status = T::SettlementStatus::Failed { reason: "BadFee".to_string() };
+        assert_eq!(ended_status(&refund), T::MinerStatus::Failed);
+
+        let owner_box = open_box();
+        let paid_in = settlement(miner_payouts(plan(10), Nat::from(1_000u32), 0, Some(&owner_box), principal(4)));
+        assert_eq!(ended_status(&paid_in), T::MinerStatus::Settled);
+    }
+
+    #[test]
+    fn box_takes_shares_until_its_payouts_are_planned() {
+        let (box_info, _) = open_box();
+        assert!(is_open(&box_info));
+        assert!(!is_open(&T::BoxInfo { is_end: true, ..box_info.clone() }));
+
+        let mut box_settlement = settlement(Vec::new());
+        box_settlement.kind = T::JobKind::BoxEnd(box_info.canister_id);
+        SETTLEMENTS.with(|settlements| settlements.borrow_mut().insert(box_settlement.kind, box_settlement));
+        assert!(!is_open(&box_info));
+    }
+
+    #[test]
+    fn jackpot_contribution_comes_off_the_top() {
+        let jackpot = account(backend(), Some(T::subaccount::jackpot().to_vec()));
+        let owner_box = open_box();
+
+        let payouts = miner_payouts(plan(10), Nat::from(1_000u32), 10, Some(&owner_box), principal(4));
+        assert_eq!(summary(&payouts)[0], (T::PayoutPurpose::JackpotContribution, jackpot, Nat::from(90u32)));
+        // The box splits the 900 left.
+        assert_eq!(summary(&payouts)[3], (T::PayoutPurpose::PrizePool, account(backend(), Some(owner_box.1)), Nat::from(215u32)));
+    }
+
+    #[test]
+    fn contribution_below_the_fee_stays_in_the_stake() {
+        let owner_box = open_box();
+        let payouts = miner_payouts(plan(10), Nat::from(100u32), 10, Some(&owner_box), principal(4));
+        assert_eq!(
+            summary(&payouts),
+            vec![
+                (T::PayoutPurpose::CreatorShare, account(principal(5), None), Nat::from(55u32)),
+                (T::PayoutPurpose::PrizePool, account(backend(), Some(owner_box.1)), Nat::from(15u32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn resent_payout_is_not_paid_twice() {
+        let ledger = ledger(10, 1_000);
+        let mut plan = plan(10);
+        plan.push(T::PayoutPurpose::AdminTax, Nat::from(500u32), backend(), None);
+        let payout = &plan.payouts[0];
+
+        let first = block_on(send(&ledger, payout));
+        let resent = block_on(send(&ledger, payout));
+
+        assert_eq!(first, Sent::Done(Nat::from(0u32)));
+        assert_eq!(resent, first);
+        assert_eq!(ledger.blocks().len(), 1);
+        assert_eq!(ledger.balance(&T::ICRCAccount { owner: backend(), subaccount: None }), Nat::from(490u32));
+    }
+
+    #[test]
+    fn payout_is_retried_after_a_transient_failure() {
+        let ledger = ledger(10, 1_000);
+        let mut plan = plan(10);
+        plan.push(T::PayoutPurpose::AdminTax, Nat::from(500u32), backend(), None);
+        ledger.fail_next(T::TransferError::TemporarilyUnavailable);
+
+        assert!(matches!(block_on(send(&ledger, &plan.payouts[0])), Sent::Retry(_)));
+        assert_eq!(block_on(send(&ledger, &plan.payouts[0])), Sent::Done(Nat::from(0u32)));
+    }
+
+    #[test]
+    fn bad_fee_reprices_pending_payouts() {
+        let ledger = ledger(20, 1_000);
+        let mut plan = plan(10);
+        plan.push(T::PayoutPurpose::AdminTax, Nat::from(500u32), backend(), None);
+        plan.push(T::PayoutPurpose::CreatorShare, Nat::from(15u32), principal(4), None);
+        let mut settlement = settlement(plan.payouts);
+
+        let fee = match block_on(send(&ledger, &settlement.payouts[0])) {
+            Sent::BadFee(fee) => fee,
+            other => panic!("Expected BadFee, got {:?}", other),
+        };
+        reprice_pending(&mut settlement, &fee);
+
+        // The gross share stays the same; the share below the new fee fails.
+        assert_eq!(settlement.payouts[0].amount.0, Nat::from(480u32));
+        assert_eq!(settlement.payouts[0].fee, Some(Nat::from(20u32)));
+        assert!(matches!(settlement.payouts[1].status, T::PayoutStatus::Failed { .. }));
+        assert_eq!(block_on(send(&ledger, &settlement.payouts[0])), Sent::Done(Nat::from(0u32)));
+    }
+
+    #[test]
+    fn insufficient_funds_fails_the_settlement() {
+        let ledger = ledger(10, 100);
+        let mut plan = plan(10);
+        plan.push(T::PayoutPurpose::AdminTax, Nat::from(500u32), backend(), None);
+        let mut settlement = settlement(plan.payouts);
+
+        match block_on(send(&ledger, &settlement.payouts[0])) {
+            Sent::Failed(reason) => settlement.payouts[0].status = T::PayoutStatus::Failed { reason },
+            other => panic!("Expected Failed, got {:?}", other),
+        }
+
+        assert!(matches!(final_status(&settlement.payouts), T::SettlementStatus::Failed { .. }));
+        assert!(ledger.blocks().is_empty());
+    }
+}
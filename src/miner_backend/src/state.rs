@@ -0,0 +1,335 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog};
+use candid::Principal;
+use std::cell::RefCell;
+use types::{self as T};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Up to v4 the registries were keyed by principal text; v5 moved them to
+// new memories keyed by principal. The v4 memories are only read, and then
+// emptied, by the v4 -> v5 migration, through the `*_v4()` accessors below.
+const USERS_V4_MEMORY: MemoryId = MemoryId::new(0);
+const BOXES_V4_MEMORY: MemoryId = MemoryId::new(1);
+const MINERS_V4_MEMORY: MemoryId = MemoryId::new(2);
+const BOX_MINER_V4_MEMORY: MemoryId = MemoryId::new(3);
+// v2 kept a u32 counter here; read once by the v2 -> v3 migration.
+const SUB_INDEX_V2_MEMORY: MemoryId = MemoryId::new(4);
+const STATE_MEMORY: MemoryId = MemoryId::new(5);
+const JOBS_V4_MEMORY: MemoryId = MemoryId::new(6);
+const JOB_SEQ_MEMORY: MemoryId = MemoryId::new(7);
+const SETTLEMENTS_V4_MEMORY: MemoryId = MemoryId::new(8);
+const JOURNAL_INDEX_V4_MEMORY: MemoryId = MemoryId::new(9);
+const JOURNAL_DATA_V4_MEMORY: MemoryId = MemoryId::new(10);
+const SPARE_CANISTERS_V4_MEMORY: MemoryId = MemoryId::new(11);
+const SUB_INDEX_MEMORY: MemoryId = MemoryId::new(12);
+const CONFIG_MEMORY: MemoryId = MemoryId::new(13);
+const TOKENS_V4_MEMORY: MemoryId = MemoryId::new(14);
+const BLOCKED_V4_MEMORY: MemoryId = MemoryId::new(15);
+const BOX_BOUNDS_MEMORY: MemoryId = MemoryId::new(16);
+const BOX_RESULTS_V4_MEMORY: MemoryId = MemoryId::new(17);
+const DRAWS_V4_MEMORY: MemoryId = MemoryId::new(18);
+const COMMITTED_SEEDS_V4_MEMORY: MemoryId = MemoryId::new(19);
+const USERS_MEMORY: MemoryId = MemoryId::new(20);
+const BOXES_MEMORY: MemoryId = MemoryId::new(21);
+const MINERS_MEMORY: MemoryId = MemoryId::new(22);
+const BOX_MINER_MEMORY: MemoryId = MemoryId::new(23);
+// v5 jobs and settlements; v6 moved them to memories 44 and 45.
+const JOBS_V5_MEMORY: MemoryId = MemoryId::new(24);
+const SETTLEMENTS_V5_MEMORY: MemoryId = MemoryId::new(25);
+const JOURNAL_INDEX_MEMORY: MemoryId = MemoryId::new(26);
+const JOURNAL_DATA_MEMORY: MemoryId = MemoryId::new(27);
+const SPARE_CANISTERS_MEMORY: MemoryId = MemoryId::new(28);
+const TOKENS_MEMORY: MemoryId = MemoryId::new(29);
+const BLOCKED_MEMORY: MemoryId = MemoryId::new(30);
+const BOX_RESULTS_MEMORY: MemoryId = MemoryId::new(31);
+const DRAWS_MEMORY: MemoryId = MemoryId::new(32);
+const COMMITTED_SEEDS_MEMORY: MemoryId = MemoryId::new(33);
+const JACKPOTS_MEMORY: MemoryId = MemoryId::new(34);
+const JACKPOT_HISTORY_INDEX_MEMORY: MemoryId = MemoryId::new(35);
+const JACKPOT_HISTORY_DATA_MEMORY: MemoryId = MemoryId::new(36);
+const JACKPOT_SETTINGS_MEMORY: MemoryId = MemoryId::new(37);
+const JACKPOT_DRAWS_INDEX_MEMORY: MemoryId = MemoryId::new(38);
+const JACKPOT_DRAWS_DATA_MEMORY: MemoryId = MemoryId::new(39);
+const TEMPLATES_MEMORY: MemoryId = MemoryId::new(40);
+const TEMPLATE_SEQ_MEMORY: MemoryId = MemoryId::new(41);
+const USER_JOURNAL_MEMORY: MemoryId = MemoryId::new(42);
+const BOX_JOURNAL_MEMORY: MemoryId = MemoryId::new(43);
+const JOBS_MEMORY: MemoryId = MemoryId::new(44);
+const SETTLEMENTS_MEMORY: MemoryId = MemoryId::new(45);
+const LEDGER_JACKPOT_HISTORY_MEMORY: MemoryId = MemoryId::new(46);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    pub static USERS: RefCell<StableBTreeMap<Principal, T::User, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(USERS_MEMORY)));
+    pub static BOXES: RefCell<StableBTreeMap<T::BoxId, T::BoxInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOXES_MEMORY)));
+    pub static MINERS: RefCell<StableBTreeMap<T::MinerId, T::Miner, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(MINERS_MEMORY)));
+    pub static BOX_MINER: RefCell<StableBTreeMap<T::MinerId, T::BoxId, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOX_MINER_MEMORY)));
+    pub static SUB_INDEX: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(memory(SUB_INDEX_MEMORY), 0).expect("Failed to init SUB_INDEX"));
+    // A memory that has never held the envelope is a fresh install, so it starts at the current version.
+    static STATE: RefCell<StableCell<T::StateEnvelope, Memory>> =
+        RefCell::new(StableCell::init(memory(STATE_MEMORY), T::StateEnvelope {
+            version: T::STATE_VERSION,
+            migrated_at: 0,
+        }).expect("Failed to init STATE"));
+    pub static JOBS: RefCell<StableBTreeMap<u64, T::Job, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(JOBS_MEMORY)));
+    static JOB_SEQ: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(memory(JOB_SEQ_MEMORY), 0).expect("Failed to init JOB_SEQ"));
+    pub static SETTLEMENTS: RefCell<StableBTreeMap<T::JobKind, T::Settlement, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(SETTLEMENTS_MEMORY)));
+    pub static JOURNAL: RefCell<StableLog<T::JournalEntry, Memory, Memory>> =
+        RefCell::new(StableLog::init(memory(JOURNAL_INDEX_MEMORY), memory(JOURNAL_DATA_MEMORY))
+            .expect("Failed to init JOURNAL"));
+    // Journal entry ids by the owners of both sides of the movement.
+    pub static USER_JOURNAL: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(USER_JOURNAL_MEMORY)));
+    // Journal entry ids by the box they belong to.
+    pub static BOX_JOURNAL: RefCell<StableBTreeMap<(T::BoxId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOX_JOURNAL_MEMORY)));
+    // Canisters installed before the config existed were pinned to the mainnet ledger.
+    static CONFIG: RefCell<StableCell<T::Config, Memory>> =
+        RefCell::new(StableCell::init(memory(CONFIG_MEMORY), T::Config {
+            ledger_canister_id: Principal::from_text(T::DEFAULT_LEDGER_CANISTER).unwrap(),
+        }).expect("Failed to init CONFIG"));
+    // Whitelisted ledgers, keyed by canister id.
+    pub static TOKENS: RefCell<StableBTreeMap<Principal, T::TokenInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TOKENS_MEMORY)));
+    pub static BOX_RESULTS: RefCell<StableBTreeMap<T::BoxId, T::BoxResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BOX_RESULTS_MEMORY)));
+    pub static DRAWS: RefCell<StableBTreeMap<T::BoxId, T::DrawTranscript, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DRAWS_MEMORY)));
+    // Secret halves of commit-reveal seeds, until their box closes.
+    static COMMITTED_SEEDS: RefCell<StableBTreeMap<T::BoxId, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(COMMITTED_SEEDS_MEMORY)));
+    static BOX_BOUNDS: RefCell<StableCell<T::BoxConfigBounds, Memory>> =
+        RefCell::new(StableCell::init(memory(BOX_BOUNDS_MEMORY), T::BoxConfigBounds {
+            min_duration_secs: 60,
+            max_duration_secs: 30 * 24 * 60 * 60,
+            min_miner_duration_secs: 30,
+            min_admin_pct: 10,
+            max_creator_pct: 65,
+            max_miners: 1_000,
+        }).expect("Failed to init BOX_BOUNDS"));
+    // Principals barred from opening miners, valued by when they were blocked.
+    pub static BLOCKED: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BLOCKED_MEMORY)));
+    // One jackpot per token, keyed by ledger.
+    pub static JACKPOTS: RefCell<StableBTreeMap<Principal, T::Jackpot, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(JACKPOTS_MEMORY)));
+    pub static JACKPOT_HISTORY: RefCell<StableLog<T::JackpotEvent, Memory, Memory>> =
+        RefCell::new(StableLog::init(memory(JACKPOT_HISTORY_INDEX_MEMORY), memory(JACKPOT_HISTORY_DATA_MEMORY))
+            .expect("Failed to init JACKPOT_HISTORY"));
+    // Jackpot history ids by the ledger of their jackpot.
+    pub static LEDGER_JACKPOT_HISTORY: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(LEDGER_JACKPOT_HISTORY_MEMORY)));
+    static JACKPOT_SETTINGS: RefCell<StableCell<T::JackpotSettings, Memory>> =
+        RefCell::new(StableCell::init(memory(JACKPOT_SETTINGS_MEMORY), T::JackpotSettings {
+            draw_interval_secs: 7 * 24 * 60 * 60,
+            contribution_pct: None,
+        }).expect("Failed to init JACKPOT_SETTINGS"));
+    pub static JACKPOT_DRAWS: RefCell<StableLog<T::JackpotDrawTranscript, Memory, Memory>> =
+        RefCell::new(StableLog::init(memory(JACKPOT_DRAWS_INDEX_MEMORY), memory(JACKPOT_DRAWS_DATA_MEMORY))
+            .expect("Failed to init JACKPOT_DRAWS"));
+    pub static TEMPLATES: RefCell<StableBTreeMap<u64, T::BoxTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TEMPLATES_MEMORY)));
+    static TEMPLATE_SEQ: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(memory(TEMPLATE_SEQ_MEMORY), 0).expect("Failed to init TEMPLATE_SEQ"));
+    // Node canisters left over from failed creations, keyed by id, valued by reclaim time.
+    static SPARE_CANISTERS: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(SPARE_CANISTERS_MEMORY)));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Bumps the subaccount counter and returns the new value.
+pub fn next_sub_index() -> u64 {
+    SUB_INDEX.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let next = *cell.get() + 1;
+        cell.set(next).expect("Failed to update SUB_INDEX");
+        next
+    })
+}
+
+pub fn sub_index_v2() -> StableCell<u32, Memory> {
+    StableCell::init(memory(SUB_INDEX_V2_MEMORY), 0).expect("Failed to init v2 SUB_INDEX")
+}
+
+pub fn users_v4() -> StableBTreeMap<String, T::legacy::UserV4, Memory> {
+    StableBTreeMap::init(memory(USERS_V4_MEMORY))
+}
+
+pub fn boxes_v4() -> StableBTreeMap<String, T::legacy::BoxInfoV4, Memory> {
+    StableBTreeMap::init(memory(BOXES_V4_MEMORY))
+}
+
+pub fn miners_v4() -> StableBTreeMap<String, T::legacy::MinerV4, Memory> {
+    StableBTreeMap::init(memory(MINERS_V4_MEMORY))
+}
+
+pub fn box_miner_v4() -> StableBTreeMap<String, String, Memory> {
+    StableBTreeMap::init(memory(BOX_MINER_V4_MEMORY))
+}
+
+pub fn jobs_v4() -> StableBTreeMap<u64, T::legacy::JobV4, Memory> {
+    StableBTreeMap::init(memory(JOBS_V4_MEMORY))
+}
+
+pub fn settlements_v4() -> StableBTreeMap<String, T::legacy::SettlementV4, Memory> {
+    StableBTreeMap::init(memory(SETTLEMENTS_V4_MEMORY))
+}
+
+pub fn jobs_v5() -> StableBTreeMap<u64, T::legacy::JobV5, Memory> {
+    StableBTreeMap::init(memory(JOBS_V5_MEMORY))
+}
+
+pub fn settlements_v5() -> StableBTreeMap<Principal, T::legacy::SettlementV5, Memory> {
+    StableBTreeMap::init(memory(SETTLEMENTS_V5_MEMORY))
+}
+
+pub fn journal_v4() -> StableLog<T::legacy::JournalEntryV4, Memory, Memory> {
+    StableLog::init(memory(JOURNAL_INDEX_V4_MEMORY), memory(JOURNAL_DATA_V4_MEMORY))
+        .expect("Failed to init v4 JOURNAL")
+}
+
+/// Empties the v4 journal once the v4 -> v5 migration has copied it.
+pub fn clear_journal_v4() {
+    StableLog::<T::legacy::JournalEntryV4, Memory, Memory>::new(memory(JOURNAL_INDEX_V4_MEMORY), memory(JOURNAL_DATA_V4_MEMORY));
+}
+
+pub fn spare_canisters_v4() -> StableBTreeMap<String, u64, Memory> {
+    StableBTreeMap::init(memory(SPARE_CANISTERS_V4_MEMORY))
+}
+
+pub fn tokens_v4() -> StableBTreeMap<String, T::TokenInfo, Memory> {
+    StableBTreeMap::init(memory(TOKENS_V4_MEMORY))
+}
+
+pub fn blocked_v4() -> StableBTreeMap<String, u64, Memory> {
+    StableBTreeMap::init(memory(BLOCKED_V4_MEMORY))
+}
+
+pub fn box_results_v4() -> StableBTreeMap<String, T::legacy::BoxResultV4, Memory> {
+    StableBTreeMap::init(memory(BOX_RESULTS_V4_MEMORY))
+}
+
+pub fn draws_v4() -> StableBTreeMap<String, T::legacy::DrawTranscriptV4, Memory> {
+    StableBTreeMap::init(memory(DRAWS_V4_MEMORY))
+}
+
+pub fn committed_seeds_v4() -> StableBTreeMap<String, Vec<u8>, Memory> {
+    StableBTreeMap::init(memory(COMMITTED_SEEDS_V4_MEMORY))
+}
+
+pub fn next_job_id() -> u64 {
+    JOB_SEQ.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let next = *cell.get() + 1;
+        cell.set(next).expect("Failed to update JOB_SEQ");
+        next
+    })
+}
+
+pub fn next_template_id() -> u64 {
+    TEMPLATE_SEQ.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let next = *cell.get() + 1;
+        cell.set(next).expect("Failed to update TEMPLATE_SEQ");
+        next
+    })
+}
+
+pub fn config() -> T::Config {
+    CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_config(config: T::Config) {
+    CONFIG.with(|cell| {
+        cell.borrow_mut().set(config).expect("Failed to update CONFIG");
+    });
+}
+
+pub fn box_bounds() -> T::BoxConfigBounds {
+    BOX_BOUNDS.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_box_bounds(bounds: T::BoxConfigBounds) {
+    BOX_BOUNDS.with(|cell| {
+        cell.borrow_mut().set(bounds).expect("Failed to update BOX_BOUNDS");
+    });
+}
+
+pub fn jackpot_settings() -> T::JackpotSettings {
+    JACKPOT_SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+pub fn set_jackpot_settings(settings: T::JackpotSettings) {
+    JACKPOT_SETTINGS.with(|cell| {
+        cell.borrow_mut().set(settings).expect("Failed to update JACKPOT_SETTINGS");
+    });
+}
+
+pub fn get_box(id: &T::BoxId) -> Option<T::BoxInfo> {
+    BOXES.with(|boxes| boxes.borrow().get(id))
+}
+
+pub fn get_miner(id: &T::MinerId) -> Option<T::Miner> {
+    MINERS.with(|miners| miners.borrow().get(id))
+}
+
+pub fn put_committed_seed(box_id: T::BoxId, seed: Vec<u8>) {
+    COMMITTED_SEEDS.with(|seeds| seeds.borrow_mut().insert(box_id, seed));
+}
+
+pub fn committed_seed(box_id: &T::BoxId) -> Option<Vec<u8>> {
+    COMMITTED_SEEDS.with(|seeds| seeds.borrow().get(box_id))
+}
+
+pub fn put_spare_canister(canister_id: Principal) {
+    SPARE_CANISTERS.with(|spares| {
+        spares.borrow_mut().insert(canister_id, ic_cdk::api::time());
+    });
+}
+
+/// Takes the first spare canister `reusable` accepts.
+pub fn take_spare_canister(reusable: impl Fn(Principal) -> bool) -> Option<Principal> {
+    SPARE_CANISTERS.with(|spares| {
+        let mut spares = spares.borrow_mut();
+        let id = spares.iter().map(|(id, _)| id).find(|id| reusable(*id))?;
+        spares.remove(&id);
+        Some(id)
+    })
+}
+
+pub fn state_version() -> u32 {
+    STATE.with(|cell| cell.borrow().get().version)
+}
+
+pub fn set_state_version(version: u32, migrated_at: u64) {
+    STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(T::StateEnvelope { version, migrated_at })
+            .expect("Failed to update STATE");
+    });
+}
+
+/// Returns true when stable memory still holds the pre-stable-structures
+/// `stable_save` blob instead of a memory manager header.
+pub fn has_legacy_layout() -> bool {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    &magic != b"MGR"
+}
@@ -0,0 +1,90 @@
+use crate::scheduler;
+use crate::state::TEMPLATES;
+use crate::open_template_box;
+use ic_cdk::api::{self, print};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use types::{self as T};
+
+thread_local! {
+    // Templates whose next box is being opened. Heap only, like the
+    // scheduler's running jobs.
+    static OPENING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+pub fn get(id: u64) -> Option<T::BoxTemplate> {
+    TEMPLATES.with(|templates| templates.borrow().get(&id))
+}
+
+pub fn list() -> Vec<T::BoxTemplate> {
+    TEMPLATES.with(|templates| templates.borrow().iter().map(|(_, template)| template).collect())
+}
+
+pub fn put(template: T::BoxTemplate) {
+    TEMPLATES.with(|templates| templates.borrow_mut().insert(template.id, template));
+}
+
+/// Queues the opening of `template`'s next box, unless it is queued already.
+pub fn schedule_next(template: &T::BoxTemplate) {
+    let kind = T::JobKind::TemplateBox { template_id: template.id };
+    if !scheduler::has_job(&kind) {
+        scheduler::schedule(kind, api::time());
+    }
+}
+
+/// Opens the next box of template `id`. Failures are kept on the template
+/// and returned so the scheduler retries them.
+pub async fn open_next(id: u64) -> Result<(), String> {
+    let template = match get(id) {
+        Some(template) if template.enabled && template.current_box.is_none() => template,
+        _ => return Ok(()),
+    };
+    if !OPENING.with(|opening| opening.borrow_mut().insert(id)) {
+        return Ok(());
+    }
+    let result = open_template_box(&template).await;
+    OPENING.with(|opening| opening.borrow_mut().remove(&id));
+    // Re-read: the template may have been disabled while the calls were out.
+    let mut template = get(id).unwrap_or(template);
+    match result {
+        Ok(box_id) => {
+            print(format!("Template {} opened box {}", id, box_id));
+            template.current_box = Some(box_id);
+            template.boxes_opened += 1;
+            template.last_error = None;
+            put(template);
+            Ok(())
+        }
+        Err(e) => {
+            let reason = format!("{:?}", e);
+            template.last_error = Some(reason.clone());
+            put(template);
+            Err(reason)
+        }
+    }
+}
+
+/// Called once a template's box is settled: frees the template and queues
+/// its next box.
+pub fn box_ended(id: u64, box_id: T::BoxId) {
+    let mut template = match get(id) {
+        Some(template) if template.current_box == Some(box_id) => template,
+        _ => return,
+    };
+    template.current_box = None;
+    if template.enabled {
+        schedule_next(&template);
+    }
+    put(template);
+}
+
+/// Called by the scheduler once opening a box ran out of retries. The
+/// template stays disabled until its owner turns it back on.
+pub fn give_up(id: u64, reason: String) {
+    if let Some(mut template) = get(id) {
+        print(format!("Template {} disabled: {}", id, reason));
+        template.enabled = false;
+        template.last_error = Some(reason);
+        put(template);
+    }
+}
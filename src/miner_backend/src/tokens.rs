@@ -0,0 +1,142 @@
+use crate::state::{self, TOKENS};
+use candid::{Nat, Principal};
+use ic_cdk::api::print;
+use ic_cdk_timers::set_timer;
+use std::future::Future;
+use std::time::Duration;
+use types::{self as T};
+use types::ledger::{ExpectedFee, IcrcLedger, Ledger};
+
+// Cached ledger fees are re-read after an hour, and at once on BadFee.
+const FEE_TTL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+pub fn get(ledger: &Principal) -> Option<T::TokenInfo> {
+    TOKENS.with(|tokens| tokens.borrow().get(ledger))
+}
+
+pub fn list() -> Vec<T::TokenInfo> {
+    TOKENS.with(|tokens| tokens.borrow().iter().map(|(_, token)| token).collect())
+}
+
+/// Listing fallback for a ledger missing from the whitelist.
+pub fn get_or_unknown(ledger: Principal) -> T::TokenInfo {
+    get(&ledger).unwrap_or(T::TokenInfo {
+        ledger_canister_id: ledger,
+        symbol: String::new(),
+        decimals: 8,
+        fee: Nat::from(0u32),
+        enabled: false,
+        updated_at: 0,
+    })
+}
+
+/// Whitelists `ledger`, or refreshes and re-enables it if already known.
+pub async fn add(ledger: Principal) -> Result<T::TokenInfo, T::BackendError> {
+    let token = IcrcLedger::fetch_token_info(ledger).await?;
+    print(format!("Token {} ({}) enabled, fee {}", token.symbol, ledger, token.fee));
+    TOKENS.with(|tokens| tokens.borrow_mut().insert(ledger, token.clone()));
+    Ok(token)
+}
+
+pub fn disable(ledger: Principal) -> Result<(), T::BackendError> {
+    let mut token = get(&ledger).ok_or(T::BackendError::TokenNotSupported { ledger })?;
+    token.enabled = false;
+    TOKENS.with(|tokens| tokens.borrow_mut().insert(ledger, token));
+    Ok(())
+}
+
+/// The configured ledger is always whitelisted. Its metadata needs calls,
+/// which init and post_upgrade cannot make, so it is fetched from a timer.
+pub fn ensure_default() {
+    let ledger = state::config().ledger_canister_id;
+    if get(&ledger).is_some() {
+        return;
+    }
+    set_timer(Duration::ZERO, move || {
+        ic_cdk::spawn(async move {
+            if let Err(e) = add(ledger).await {
+                print(format!("Failed to whitelist default ledger {}: {:?}", ledger, e));
+            }
+        })
+    });
+}
+
+/// Client for any known ledger, enabled or not, so open boxes keep settling.
+/// Uses the cached fee; see [`fresh_client`].
+pub fn client(ledger: Principal) -> Result<IcrcLedger, T::BackendError> {
+    let token = get(&ledger).ok_or(T::BackendError::TokenNotSupported { ledger })?;
+    Ok(IcrcLedger { canister_id: ledger, fee: token.fee })
+}
+
+/// Like [`client`], but re-reads `icrc1_fee` first if the cached one is stale.
+/// A failed read keeps the cached fee; BadFee will correct it later.
+pub async fn fresh_client(ledger: Principal) -> Result<IcrcLedger, T::BackendError> {
+    let token = get(&ledger).ok_or(T::BackendError::TokenNotSupported { ledger })?;
+    if ic_cdk::api::time().saturating_sub(token.updated_at) > FEE_TTL_NANOS {
+        match IcrcLedger::fetch_fee(ledger).await {
+            Ok(fee) => set_fee(ledger, fee),
+            Err(e) => print(format!("Keeping cached fee of {}: {}", ledger, e)),
+        }
+    }
+    client(ledger)
+}
+
+/// Updates the cached fee of `ledger`, e.g. from a BadFee error.
+pub fn set_fee(ledger: Principal, fee: Nat) {
+    if let Some(mut token) = get(&ledger) {
+        if token.fee != fee {
+            print(format!("Fee of {} changed from {} to {}", ledger, token.fee, fee));
+        }
+        token.fee = fee;
+        token.updated_at = ic_cdk::api::time();
+        TOKENS.with(|tokens| tokens.borrow_mut().insert(ledger, token));
+    }
+}
+
+/// Sends with the cached fee of `ledger`. A stale fee is turned down with
+/// BadFee and nothing moves, so it is sent again with the ledger's. Returns
+/// the fee of the last send with its result.
+pub async fn with_fee_retry<L, F, Fut, E>(ledger: &L, mut send: F) -> (Nat, Result<Nat, E>)
+where
+    L: Ledger,
+    F: FnMut(Nat) -> Fut,
+    Fut: Future<Output = Result<Nat, E>>,
+    E: ExpectedFee,
+{
+    let mut fee = ledger.fee();
+    loop {
+        match send(fee.clone()).await {
+            Err(failure) => match failure.expected_fee() {
+                Some(expected_fee) if expected_fee != fee => {
+                    set_fee(ledger.canister_id(), expected_fee.clone());
+                    fee = expected_fee;
+                }
+                _ => return (fee, Err(failure)),
+            },
+            result => return (fee, result),
+        }
+    }
+}
+
+pub fn box_ledger(box_info: &T::BoxInfo) -> Principal {
+    box_info.ledger.unwrap_or_else(|| state::config().ledger_canister_id)
+}
+
+/// Client for the ledger box `box_id` is paid in; miners use their box's.
+pub async fn client_for_box(box_id: &T::BoxId) -> Result<IcrcLedger, T::BackendError> {
+    let ledger = state::get_box(box_id)
+        .map(|box_info| box_ledger(&box_info))
+        .unwrap_or_else(|| state::config().ledger_canister_id);
+    fresh_client(ledger).await
+}
+
+/// `amount` in the smallest unit of an 8-decimal token, scaled to `token`.
+pub fn scale_from_e8s(amount: u64, token: &T::TokenInfo) -> Nat {
+    let amount = Nat::from(amount);
+    let decimals = token.decimals as u32;
+    if decimals >= 8 {
+        amount * Nat(Nat::from(10u64).0.pow(decimals - 8))
+    } else {
+        amount / Nat(Nat::from(10u64).0.pow(8 - decimals))
+    }
+}
@@ -0,0 +1,295 @@
+//! The box draw, kept here so anyone can replay a [`DrawTranscript`] with
+//! [`verify`], or a [`JackpotDrawTranscript`] with [`verify_jackpot`], and
+//! get the same winners the backend picked.
+
+use crate::{DrawCandidate, DrawTranscript, JackpotDrawTranscript};
+use candid::Nat;
+use sha2::{Digest, Sha256};
+
+/// Version 1: sha256(seed ‖ counter) stream, rejection sampling into the
+/// total weight, winners drawn in order without replacement.
+pub const ALGORITHM_VERSION: u32 = 1;
+
+/// Stream of uniform numbers expanded from a 32-byte seed, so a draw can be
+/// replayed from its seed.
+pub struct Sampler {
+    seed: [u8; 32],
+    counter: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Sampler { seed, counter: 0 }
+    }
+
+    fn next_u128(&mut self) -> u128 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed);
+        hasher.update(self.counter.to_be_bytes());
+        self.counter += 1;
+        let digest = hasher.finalize();
+        u128::from_be_bytes(digest[..16].try_into().unwrap())
+    }
+
+    /// Uniform in `0..bound`. Draws past the largest multiple of `bound` are
+    /// rejected, so there is no modulo bias.
+    pub fn below(&mut self, bound: u128) -> u128 {
+        assert!(bound > 0, "empty range");
+        let limit = u128::MAX - (u128::MAX % bound);
+        loop {
+            let r = self.next_u128();
+            if r < limit {
+                return r % bound;
+            }
+        }
+    }
+}
+
+/// Picks up to `count` distinct indexes in order, each pick weighted among
+/// the ones left. Stops early when nothing with a weight is left.
+pub fn draw_without_replacement(weights: &[u128], count: usize, sampler: &mut Sampler) -> Vec<usize> {
+    let mut weights = weights.to_vec();
+    let mut picked = Vec::new();
+    while picked.len() < count {
+        match weighted_index(&weights, sampler) {
+            Some(i) => {
+                picked.push(i);
+                weights[i] = 0;
+            }
+            None => break,
+        }
+    }
+    picked
+}
+
+/// Picks an index with probability `weights[i] / sum(weights)`.
+/// None when there is nothing to pick from.
+pub fn weighted_index(weights: &[u128], sampler: &mut Sampler) -> Option<usize> {
+    let total = weights.iter().fold(0u128, |sum, w| sum.saturating_add(*w));
+    if total == 0 {
+        return None;
+    }
+    let mut ticket = sampler.below(total);
+    for (i, weight) in weights.iter().enumerate() {
+        if ticket < *weight {
+            return Some(i);
+        }
+        ticket -= weight;
+    }
+    None
+}
+
+/// The draw the backend runs: `count` winners out of `weights`, by index.
+pub fn run(seed: [u8; 32], weights: &[u128], count: usize) -> Vec<usize> {
+    draw_without_replacement(weights, count, &mut Sampler::new(seed))
+}
+
+/// The public commitment to a seed.
+pub fn commit(seed: &[u8]) -> Vec<u8> {
+    Sha256::digest(seed).to_vec()
+}
+
+/// The draw seed of a commit-reveal box. Neither half alone decides it.
+pub fn combine_seeds(committed_seed: &[u8], close_seed: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(committed_seed);
+    hasher.update(close_seed);
+    hasher.finalize().into()
+}
+
+/// Recomputes the winners of `transcript` from its seed and candidates, and
+/// for commit-reveal draws checks the seed against the commitment.
+pub fn verify(transcript: &DrawTranscript) -> Result<(), String> {
+    if transcript.algorithm_version != ALGORITHM_VERSION {
+        return Err(format!("Unknown algorithm version {}", transcript.algorithm_version));
+    }
+    if let Some(reveal) = &transcript.reveal {
+        if commit(&reveal.committed_seed) != reveal.commit {
+            return Err("Committed seed does not match the commitment".to_string());
+        }
+        if combine_seeds(&reveal.committed_seed, &reveal.close_seed).to_vec() != transcript.seed {
+            return Err("Seed is not the combination of the revealed seeds".to_string());
+        }
+    }
+    let winners = replay(&transcript.seed, &transcript.candidates, transcript.places as usize)?;
+    if winners == transcript.winners {
+        Ok(())
+    } else {
+        Err(format!("Expected winners {:?}, transcript says {:?}", winners, transcript.winners))
+    }
+}
+
+/// Recomputes the winner of a jackpot draw from its seed and candidates.
+pub fn verify_jackpot(transcript: &JackpotDrawTranscript) -> Result<(), String> {
+    if transcript.algorithm_version != ALGORITHM_VERSION {
+        return Err(format!("Unknown algorithm version {}", transcript.algorithm_version));
+    }
+    let winners = replay(&transcript.seed, &transcript.candidates, 1)?;
+    if winners == [transcript.winner] {
+        Ok(())
+    } else {
+        Err(format!("Expected winner {:?}, transcript says {}", winners, transcript.winner))
+    }
+}
+
+fn replay(seed: &[u8], candidates: &[DrawCandidate], places: usize) -> Result<Vec<u32>, String> {
+    let seed: [u8; 32] = seed.try_into().map_err(|_| "Seed is not 32 bytes".to_string())?;
+    let weights = candidates
+        .iter()
+        .map(|candidate| weight_of(&candidate.weight))
+        .collect::<Result<Vec<u128>, String>>()?;
+    Ok(run(seed, &weights, places).into_iter().map(|index| index as u32).collect())
+}
+
+pub fn weight_of(weight: &Nat) -> Result<u128, String> {
+    u128::try_from(weight.0.clone()).map_err(|_| format!("Weight {} does not fit u128", weight))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BoxId, DrawMode, MinerId, SeedReveal};
+    use candid::Principal;
+
+    fn weights() -> Vec<u128> {
+        (1..=20).map(|i| i * 1_000).collect()
+    }
+
+    // A transcript the way the backend records one, winners included.
+    fn transcript(seed: [u8; 32], weights: &[u128], places: u32, reveal: Option<SeedReveal>) -> DrawTranscript {
+        DrawTranscript {
+            box_id: BoxId(Principal::from_slice(&[1; 10])),
+            algorithm_version: ALGORITHM_VERSION,
+            seed: seed.to_vec(),
+            reveal,
+            mode: DrawMode::StakeWeighted,
+            candidates: weights
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| DrawCandidate {
+                    miner_id: MinerId(Principal::from_slice(&[i as u8 + 2; 10])),
+                    stake: None,
+                    weight: Nat::from(*weight),
+                })
+                .collect(),
+            places,
+            winners: run(seed, weights, places as usize).into_iter().map(|i| i as u32).collect(),
+            drawn_at: 0,
+        }
+    }
+
+    #[test]
+    fn below_rejects_draws_past_the_last_whole_range() {
+        // Just over half of u128, so nearly half the raw draws fall past the
+        // largest multiple of the range and must be drawn again.
+        let bound = (1u128 << 127) + 1;
+        let limit = u128::MAX - (u128::MAX % bound);
+        let seed = (0..=u8::MAX)
+            .map(|byte| [byte; 32])
+            .find(|seed| Sampler::new(*seed).next_u128() >= limit)
+            .unwrap();
+        let mut raw = Sampler::new(seed);
+        let mut rejected = 0;
+        let kept = loop {
+            let r = raw.next_u128();
+            if r < limit {
+                break r;
+            }
+            rejected += 1;
+        };
+        assert!(rejected > 0);
+        let mut sampler = Sampler::new(seed);
+        assert_eq!(sampler.below(bound), kept % bound);
+        assert_eq!(sampler.counter, rejected + 1);
+    }
+
+    #[test]
+    fn weighted_index_follows_skewed_weights() {
+        let weights = [1u128, 10, 0, 100, 889];
+        let total: u128 = weights.iter().sum();
+        let rounds = 100_000;
+        let mut sampler = Sampler::new([5; 32]);
+        let mut counts = [0u32; 5];
+        for _ in 0..rounds {
+            counts[weighted_index(&weights, &mut sampler).unwrap()] += 1;
+        }
+        assert_eq!(counts[2], 0);
+        for (weight, count) in weights.iter().zip(counts) {
+            let p = *weight as f64 / total as f64;
+            let expected = rounds as f64 * p;
+            // Five standard deviations of a binomial count.
+            let slack = 5.0 * (rounds as f64 * p * (1.0 - p)).sqrt() + 1.0;
+            assert!((count as f64 - expected).abs() <= slack, "weight {} drawn {} times, expected {}", weight, count, expected);
+        }
+    }
+
+    #[test]
+    fn verify_replays_what_run_drew() {
+        let transcript = transcript([3; 32], &weights(), 3, None);
+        assert_eq!(transcript.winners.len(), 3);
+        assert_eq!(verify(&transcript), Ok(()));
+    }
+
+    #[test]
+    fn verify_rejects_a_changed_seed() {
+        let mut transcript = transcript([3; 32], &weights(), 3, None);
+        transcript.seed[0] ^= 1;
+        assert!(verify(&transcript).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_a_changed_weight() {
+        let mut transcript = transcript([3; 32], &weights(), 3, None);
+        // The first winner could not have won without a weight.
+        let first = transcript.winners[0] as usize;
+        transcript.candidates[first].weight = Nat::from(0u32);
+        assert!(verify(&transcript).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_a_changed_winner() {
+        let mut transcript = transcript([3; 32], &weights(), 3, None);
+        let loser = (0..20).find(|i| !transcript.winners.contains(i)).unwrap();
+        transcript.winners[0] = loser;
+        assert!(verify(&transcript).is_err());
+    }
+
+    fn revealed(committed_seed: [u8; 32], close_seed: [u8; 32]) -> DrawTranscript {
+        let reveal = SeedReveal {
+            commit: commit(&committed_seed),
+            committed_seed: committed_seed.to_vec(),
+            close_seed: close_seed.to_vec(),
+        };
+        transcript(combine_seeds(&committed_seed, &close_seed), &weights(), 3, Some(reveal))
+    }
+
+    #[test]
+    fn revealed_seed_matches_its_commit() {
+        let transcript = revealed([7; 32], [9; 32]);
+        let reveal = transcript.reveal.as_ref().unwrap();
+        assert_eq!(commit(&reveal.committed_seed), reveal.commit);
+        assert_ne!(reveal.commit, reveal.committed_seed);
+        assert_eq!(verify(&transcript), Ok(()));
+    }
+
+    #[test]
+    fn combined_seed_depends_on_both_halves() {
+        let seed = combine_seeds(&[7; 32], &[9; 32]);
+        assert_ne!(seed, combine_seeds(&[8; 32], &[9; 32]));
+        assert_ne!(seed, combine_seeds(&[7; 32], &[10; 32]));
+    }
+
+    #[test]
+    fn verify_rejects_a_wrong_committed_seed() {
+        let mut transcript = revealed([7; 32], [9; 32]);
+        transcript.reveal.as_mut().unwrap().committed_seed = vec![8; 32];
+        assert_eq!(verify(&transcript), Err("Committed seed does not match the commitment".to_string()));
+    }
+
+    #[test]
+    fn verify_rejects_a_wrong_close_seed() {
+        let mut transcript = revealed([7; 32], [9; 32]);
+        transcript.reveal.as_mut().unwrap().close_seed = vec![10; 32];
+        assert_eq!(verify(&transcript), Err("Seed is not the combination of the revealed seeds".to_string()));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    BackendError, ICRC2Allowance, ICRC2AllowanceArgs, ICRC2TransferFromArgs, ICRC2TransferFromError, ICRC2TransferFromResult,
+    ICRCAccount, SupportedStandard, TokenInfo, TranferResult, TransferArg, TransferError,
+};
+use candid::{Nat, Principal};
+use ic_cdk::api::call::call;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// A ledger call that did not go through: either the call itself failed or
+/// the ledger rejected it.
+#[derive(Debug, Clone)]
+pub enum LedgerFailure<E> {
+    Call(String),
+    Rejected(E),
+}
+
+pub type TransferFailure = LedgerFailure<TransferError>;
+pub type TransferFromFailure = LedgerFailure<ICRC2TransferFromError>;
+
+/// Failures that tell whether the ledger turned down a stale fee.
+pub trait ExpectedFee {
+    /// The ledger's current fee, when the transfer was sent with a stale one.
+    fn expected_fee(&self) -> Option<Nat>;
+}
+
+impl TransferFailure {
+    /// Failures worth resending with the same memo and created_at_time.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LedgerFailure::Call(_)
+                | LedgerFailure::Rejected(TransferError::TemporarilyUnavailable)
+                | LedgerFailure::Rejected(TransferError::CreatedInFuture { .. })
+        )
+    }
+}
+
+impl ExpectedFee for TransferFailure {
+    fn expected_fee(&self) -> Option<Nat> {
+        match self {
+            LedgerFailure::Rejected(TransferError::BadFee { expected_fee }) => Some(expected_fee.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl ExpectedFee for TransferFromFailure {
+    fn expected_fee(&self) -> Option<Nat> {
+        match self {
+            LedgerFailure::Rejected(ICRC2TransferFromError::BadFee { expected_fee }) => Some(expected_fee.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The ICRC-1/ICRC-2 calls the backend makes. Amounts are in the ledger's
+/// smallest unit; `transfer` and `transfer_from` return the block index.
+#[allow(async_fn_in_trait)]
+pub trait Ledger {
+    async fn balance_of(&self, account: ICRCAccount) -> Result<Nat, String>;
+    async fn allowance(&self, account: ICRCAccount, spender: ICRCAccount) -> Result<Nat, String>;
+    async fn transfer(&self, args: TransferArg) -> Result<Nat, TransferFailure>;
+    async fn transfer_from(&self, args: ICRC2TransferFromArgs) -> Result<Nat, TransferFromFailure>;
+    fn fee(&self) -> Nat;
+    fn canister_id(&self) -> Principal;
+}
+
+/// An ICRC-1/ICRC-2 ledger canister.
+pub struct IcrcLedger {
+    pub canister_id: Principal,
+    pub fee: Nat,
+}
+
+impl IcrcLedger {
+    /// Reads symbol, decimals and fee from the ledger, checking it supports ICRC-2.
+    pub async fn fetch_token_info(canister_id: Principal) -> Result<TokenInfo, BackendError> {
+        let (standards,) = call::<(), (Vec<SupportedStandard>,)>(canister_id, "icrc1_supported_standards", ())
+            .await
+            .map_err(|e| BackendError::LedgerCall(format!("icrc1_supported_standards Call failed: {:?}", e)))?;
+        if !standards.iter().any(|standard| standard.name == "ICRC-2") {
+            return Err(BackendError::TokenNotSupported { ledger: canister_id });
+        }
+        let (symbol,) = call::<(), (String,)>(canister_id, "icrc1_symbol", ())
+            .await
+            .map_err(|e| BackendError::LedgerCall(format!("icrc1_symbol Call failed: {:?}", e)))?;
+        let (decimals,) = call::<(), (u8,)>(canister_id, "icrc1_decimals", ())
+            .await
+            .map_err(|e| BackendError::LedgerCall(format!("icrc1_decimals Call failed: {:?}", e)))?;
+        let fee = Self::fetch_fee(canister_id).await.map_err(BackendError::LedgerCall)?;
+        Ok(TokenInfo {
+            ledger_canister_id: canister_id,
+            symbol,
+            decimals,
+            fee,
+            enabled: true,
+            updated_at: ic_cdk::api::time(),
+        })
+    }
+
+    pub async fn fetch_fee(canister_id: Principal) -> Result<Nat, String> {
+        match call::<(), (Nat,)>(canister_id, "icrc1_fee", ()).await {
+            Ok((fee,)) => Ok(fee),
+            Err(e) => Err(format!("icrc1_fee Call failed: {:?}", e)),
+        }
+    }
+}
+
+impl Ledger for IcrcLedger {
+    async fn balance_of(&self, account: ICRCAccount) -> Result<Nat, String> {
+        match call::<(ICRCAccount,), (Nat,)>(self.canister_id, "icrc1_balance_of", (account,)).await {
+            Ok((balance,)) => Ok(balance),
+            Err(e) => Err(format!("icrc1_balance_of Call failed: {:?}", e)),
+        }
+    }
+
+    async fn allowance(&self, account: ICRCAccount, spender: ICRCAccount) -> Result<Nat, String> {
+        let args = ICRC2AllowanceArgs { account, spender };
+        match call::<(ICRC2AllowanceArgs,), (ICRC2Allowance,)>(self.canister_id, "icrc2_allowance", (args,)).await {
+            Ok((allowance,)) => Ok(allowance.allowance),
+            Err(e) => Err(format!("icrc2_allowance Call failed: {:?}", e)),
+        }
+    }
+
+    async fn transfer(&self, args: TransferArg) -> Result<Nat, TransferFailure> {
+        match call::<(TransferArg,), (TranferResult,)>(self.canister_id, "icrc1_transfer", (args,)).await {
+            Ok((TranferResult::Ok(index),)) => Ok(index),
+            Ok((TranferResult::Err(e),)) => Err(LedgerFailure::Rejected(e)),
+            Err(e) => Err(LedgerFailure::Call(format!("icrc1_transfer Call failed: {:?}", e))),
+        }
+    }
+
+    async fn transfer_from(&self, args: ICRC2TransferFromArgs) -> Result<Nat, TransferFromFailure> {
+        match call::<(ICRC2TransferFromArgs,), (ICRC2TransferFromResult,)>(self.canister_id, "icrc2_transfer_from", (args,)).await {
+            Ok((ICRC2TransferFromResult::Ok(index),)) => Ok(index),
+            Ok((ICRC2TransferFromResult::Err(e),)) => Err(LedgerFailure::Rejected(e)),
+            Err(e) => Err(LedgerFailure::Call(format!("icrc2_transfer_from Call failed: {:?}", e))),
+        }
+    }
+
+    fn fee(&self) -> Nat {
+        self.fee.clone()
+    }
+
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+}
+
+type AccountKey = (Principal, Vec<u8>);
+// What the ledger deduplicates a transfer on: (from, memo, created_at_time).
+type DedupKey = (AccountKey, Vec<u8>, u64);
+
+fn account_key(account: &ICRCAccount) -> AccountKey {
+    (account.owner, account.subaccount.clone().unwrap_or_else(|| vec![0; 32]))
+}
+
+/// In-memory ledger for exercising payout code off-chain. `owner` plays the
+/// calling canister: it sends `transfer`s and spends ICRC-2 allowances.
+/// Charges `fee` on every transfer and deduplicates on
+/// (from, memo, created_at_time) the way the real ledger does.
+pub struct MockLedger {
+    pub canister_id: Principal,
+    pub owner: Principal,
+    pub fee: Nat,
+    balances: RefCell<BTreeMap<AccountKey, Nat>>,
+    allowances: RefCell<BTreeMap<(AccountKey, AccountKey), Nat>>,
+    seen: RefCell<BTreeMap<DedupKey, Nat>>,
+    failures: RefCell<Vec<TransferError>>,
+    blocks: RefCell<Vec<TransferArg>>,
+}
+
+impl MockLedger {
+    pub fn new(canister_id: Principal, owner: Principal, fee: Nat) -> Self {
+        MockLedger {
+            canister_id,
+            owner,
+            fee,
+            balances: RefCell::new(BTreeMap::new()),
+            allowances: RefCell::new(BTreeMap::new()),
+            seen: RefCell::new(BTreeMap::new()),
+            failures: RefCell::new(Vec::new()),
+            blocks: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn mint(&self, account: &ICRCAccount, amount: Nat) {
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances.entry(account_key(account)).or_insert_with(|| Nat::from(0u32));
+        *balance = balance.clone() + amount;
+    }
+
+    pub fn approve(&self, account: &ICRCAccount, spender: &ICRCAccount, amount: Nat) {
+        self.allowances.borrow_mut().insert((account_key(account), account_key(spender)), amount);
+    }
+
+    pub fn balance(&self, account: &ICRCAccount) -> Nat {
+        self.balances.borrow().get(&account_key(account)).cloned().unwrap_or_else(|| Nat::from(0u32))
+    }
+
+    /// Makes the next transfer fail with `error`, before anything moves.
+    pub fn fail_next(&self, error: TransferError) {
+        self.failures.borrow_mut().push(error);
+    }
+
+    /// Every transfer applied so far, in block order.
+    pub fn blocks(&self) -> Vec<TransferArg> {
+        self.blocks.borrow().clone()
+    }
+
+    fn apply(&self, from: &ICRCAccount, args: TransferArg) -> Result<Nat, TransferError> {
+        if let Some(error) = self.failures.borrow_mut().pop() {
+            return Err(error);
+        }
+        if let Some(fee) = &args.fee {
+            if *fee != self.fee {
+                return Err(TransferError::BadFee { expected_fee: self.fee.clone() });
+            }
+        }
+        let from_key = account_key(from);
+        let dedup_key = match (&args.memo, args.created_at_time) {
+            (Some(memo), Some(created_at_time)) => Some((from_key.clone(), memo.clone(), created_at_time)),
+            _ => None,
+        };
+        if let Some(block_index) = dedup_key.as_ref().and_then(|key| self.seen.borrow().get(key).cloned()) {
+            return Err(TransferError::Duplicate { duplicate_of: block_index });
+        }
+        let balance = self.balance(from);
+        let total = args.amount.clone() + self.fee.clone();
+        if balance < total {
+            return Err(TransferError::InsufficientFunds { balance });
+        }
+        self.balances.borrow_mut().insert(from_key, balance - total);
+        self.mint(&args.to, args.amount.clone());
+
+        let block_index = Nat::from(self.blocks.borrow().len() as u64);
+        self.blocks.borrow_mut().push(args);
+        if let Some(key) = dedup_key {
+            self.seen.borrow_mut().insert(key, block_index.clone());
+        }
+        Ok(block_index)
+    }
+}
+
+impl Ledger for MockLedger {
+    async fn balance_of(&self, account: ICRCAccount) -> Result<Nat, String> {
+        Ok(self.balance(&account))
+    }
+
+    async fn allowance(&self, account: ICRCAccount, spender: ICRCAccount) -> Result<Nat, String> {
+        Ok(self
+            .allowances
+            .borrow()
+            .get(&(account_key(&account), account_key(&spender)))
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0u32)))
+    }
+
+    async fn transfer(&self, args: TransferArg) -> Result<Nat, TransferFailure> {
+        let from = ICRCAccount { owner: self.owner, subaccount: args.from_subaccount.clone() };
+        self.apply(&from, args).map_err(LedgerFailure::Rejected)
+    }
+
+    async fn transfer_from(&self, args: ICRC2TransferFromArgs) -> Result<Nat, TransferFromFailure> {
+        let spender = ICRCAccount { owner: self.owner, subaccount: args.spender_subaccount.clone() };
+        let key = (account_key(&args.from), account_key(&spender));
+        let allowance = self.allowances.borrow().get(&key).cloned().unwrap_or_else(|| Nat::from(0u32));
+        let needed = args.amount.clone() + self.fee.clone();
+        if allowance < needed {
+            return Err(LedgerFailure::Rejected(ICRC2TransferFromError::InsufficientAllowance { allowance }));
+        }
+        let transfer = TransferArg {
+            from_subaccount: args.from.subaccount.clone(),
+            to: args.to,
+            amount: args.amount,
+            fee: args.fee,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        };
+        match self.apply(&args.from, transfer) {
+            Ok(block_index) => {
+                self.allowances.borrow_mut().insert(key, allowance - needed);
+                Ok(block_index)
+            }
+            Err(e) => Err(LedgerFailure::Rejected(match e {
+                TransferError::InsufficientFunds { balance } => ICRC2TransferFromError::InsufficientFunds { balance },
+                TransferError::BadFee { expected_fee } => ICRC2TransferFromError::BadFee { expected_fee },
+                TransferError::Duplicate { duplicate_of } => ICRC2TransferFromError::Duplicate { duplicate_of },
+                TransferError::TemporarilyUnavailable => ICRC2TransferFromError::TemporarilyUnavailable,
+                other => ICRC2TransferFromError::GenericError { error_code: Nat::from(0u32), message: format!("{:?}", other) },
+            })),
+        }
+    }
+
+    fn fee(&self) -> Nat {
+        self.fee.clone()
+    }
+
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+}
@@ -0,0 +1,213 @@
+//! Frozen record shapes from earlier state versions. These must never change:
+//! migrations decode old stable memory with them.
+use crate::{BoxConfig, ICRCAccount, JournalReason, MinerStatus, PayoutRecord, SeedReveal, SettlementStatus, DrawMode};
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// v1 user record.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UserV1 {
+    pub nickname: String,
+}
+
+/// v1 box record.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BoxInfoV1 {
+    pub user: String,
+    pub canister_id: String,
+    pub reg_date: u64,
+    pub end_date: u64,
+    pub is_end: bool
+}
+
+/// v1 miner record.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MinerV1 {
+    pub user: String,
+    pub box_id: String,
+    pub canister_id: String,
+    pub reg_date: u64,
+    pub end_date: u64,
+    pub is_end: bool
+}
+
+/// v1 state: the tuple `pre_upgrade` wrote with `ic_cdk::storage::stable_save`
+/// as `(users, boxes, miners, box_miner, sub_index)`.
+pub type StateV1 = (
+    BTreeMap<String, UserV1>,
+    BTreeMap<String, BoxInfoV1>,
+    BTreeMap<String, MinerV1>,
+    BTreeMap<String, String>,
+    u32,
+);
+
+/// v2-v4 user record; the v4 maps key users by principal text.
+pub type UserV4 = UserV1;
+
+/// v4 box record, keyed by canister id text.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BoxInfoV4 {
+    pub user: String,
+    pub canister_id: String,
+    pub reg_date: u64,
+    pub end_date: u64,
+    pub is_end: bool,
+    pub subaccount: Option<Vec<u8>>,
+    pub ledger: Option<Principal>,
+    pub config: Option<BoxConfig>,
+    pub seed_commit: Option<Vec<u8>>,
+}
+
+/// v4 miner record, keyed by canister id text.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MinerV4 {
+    pub user: String,
+    pub box_id: String,
+    pub canister_id: String,
+    pub reg_date: u64,
+    pub end_date: u64,
+    pub is_end: bool,
+    pub subaccount: Option<Vec<u8>>,
+    pub stake: Option<Nat>,
+    pub status: Option<MinerStatus>,
+}
+
+/// v2-v5 job kind; what it was for was kept apart in `target`. v4 only
+/// had `MinerEnd` and `BoxEnd`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum JobKindV5 {
+    MinerEnd,
+    BoxEnd,
+    JackpotDraw,
+    TemplateBox { template_id: u64 },
+}
+
+/// v4 scheduler job.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct JobV4 {
+    pub id: u64,
+    pub kind: JobKindV5,
+    pub target: String,
+    pub due_at: u64,
+    pub attempts: u32,
+}
+
+/// v4 settlement, keyed by its target's canister id text.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SettlementV4 {
+    pub kind: JobKindV5,
+    pub target: String,
+    pub status: SettlementStatus,
+    pub payouts: Vec<PayoutRecord>,
+    pub updated_at: u64,
+}
+
+/// v5 scheduler job. `target` is the canister id of the box or miner it
+/// settles, the ledger of a jackpot draw, or the owner of a template.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct JobV5 {
+    pub id: u64,
+    pub kind: JobKindV5,
+    pub target: Principal,
+    pub due_at: u64,
+    pub attempts: u32,
+}
+
+/// v5 settlement, keyed by its target.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SettlementV5 {
+    pub kind: JobKindV5,
+    pub target: Principal,
+    pub status: SettlementStatus,
+    pub payouts: Vec<PayoutRecord>,
+    pub updated_at: u64,
+}
+
+/// v4 journal entry.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct JournalEntryV4 {
+    pub id: u64,
+    pub reason: JournalReason,
+    pub amount: Nat,
+    pub from: ICRCAccount,
+    pub to: ICRCAccount,
+    pub block_index: Nat,
+    pub timestamp: u64,
+    pub box_id: Option<String>,
+    pub miner_id: Option<String>,
+    pub ledger: Option<Principal>,
+}
+
+/// v4 draw candidate.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DrawCandidateV4 {
+    pub miner_id: String,
+    pub stake: Option<Nat>,
+    pub weight: Nat,
+}
+
+/// v4 draw transcript, keyed by box canister id text.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DrawTranscriptV4 {
+    pub box_id: String,
+    pub algorithm_version: u32,
+    pub seed: Vec<u8>,
+    pub reveal: Option<SeedReveal>,
+    pub mode: DrawMode,
+    pub candidates: Vec<DrawCandidateV4>,
+    pub places: u32,
+    pub winners: Vec<u32>,
+    pub drawn_at: u64,
+}
+
+/// v4 box winner.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BoxWinnerV4 {
+    pub place: u32,
+    pub miner_id: String,
+    pub user: String,
+    pub amount: Nat,
+}
+
+/// v4 box result, keyed by box canister id text.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BoxResultV4 {
+    pub box_id: String,
+    pub winners: Vec<BoxWinnerV4>,
+    pub refund: Option<Nat>,
+    pub decided_at: u64,
+    pub reveal: Option<SeedReveal>,
+}
+
+impl From<BoxInfoV1> for BoxInfoV4 {
+    fn from(box_info: BoxInfoV1) -> Self {
+        BoxInfoV4 {
+            user: box_info.user,
+            canister_id: box_info.canister_id,
+            reg_date: box_info.reg_date,
+            end_date: box_info.end_date,
+            is_end: box_info.is_end,
+            subaccount: None,
+            ledger: None,
+            config: None,
+            seed_commit: None,
+        }
+    }
+}
+
+impl From<MinerV1> for MinerV4 {
+    fn from(miner: MinerV1) -> Self {
+        MinerV4 {
+            user: miner.user,
+            box_id: miner.box_id,
+            canister_id: miner.canister_id,
+            reg_date: miner.reg_date,
+            end_date: miner.end_date,
+            is_end: miner.is_end,
+            subaccount: None,
+            stake: None,
+            status: None,
+        }
+    }
+}
@@ -1,43 +1,906 @@
 use candid::{CandidType};
 use serde::{Deserialize, Serialize};
 use candid::{Nat, Principal};
+use candid::{Decode, Encode};
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use std::fmt;
 
-pub const LEDGER_CANISTER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+pub mod draw;
+pub mod ledger;
+pub mod legacy;
+pub mod subaccount;
+
+/// Mainnet ICP ledger, used when the backend is installed without a ledger argument.
+pub const DEFAULT_LEDGER_CANISTER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// Layout version of the backend's stable memory.
+/// 1 - single `stable_save` tuple, see [`legacy::StateV1`].
+/// 2 - registries in stable maps with candid-encoded records.
+/// 3 - u64 subaccount counter; boxes and miners record their subaccount.
+/// 4 - boxes record the ledger of their token.
+/// 5 - ids and users are principals; maps keyed by [`BoxId`], [`MinerId`]
+///     and `Principal` instead of text.
+/// 6 - jobs and settlements name what they are for by a typed [`JobKind`];
+///     settlements are keyed by it.
+/// 7 - the journal is indexed by user and by box.
+/// 8 - the jackpot history is indexed by ledger.
+pub const STATE_VERSION: u32 = 8;
+
+/// Longest nickname a user can register, in characters.
+pub const MAX_NICKNAME_LEN: usize = 64;
+
+/// Header kept in its own stable cell so an upgrade knows which layout it is reading.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StateEnvelope {
+    pub version: u32,
+    pub migrated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub ledger_canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpgradeArgs {
+    pub ledger_canister_id: Option<Principal>,
+}
+
+/// Install/upgrade argument of the backend canister.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum BackendArgs {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
+}
+
+/// Settings that come from the install/upgrade argument.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub ledger_canister_id: Principal,
+}
+
+/// An ICRC-2 ledger the backend accepts, with the metadata read from it.
+/// Disabled tokens keep settling existing boxes but cannot open new ones.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenInfo {
+    pub ledger_canister_id: Principal,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: Nat,
+    pub enabled: bool,
+    pub updated_at: u64,
+}
+
+/// How a box picks its winner.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum DrawMode {
+    /// Odds proportional to each miner's stake.
+    StakeWeighted,
+    /// Every miner has the same odds.
+    Uniform,
+}
+
+/// Where a box's draw seed comes from.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RandomnessMode {
+    /// One `raw_rand` taken when the box closes.
+    Single,
+    /// A seed committed to when the box opens, combined with a second
+    /// `raw_rand` at close. See [`draw::combine_seeds`].
+    CommitReveal,
+}
+
+/// Most places a prize table can have.
+pub const MAX_PRIZE_PLACES: usize = 10;
+
+/// How a box's pot is split between its winners.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum PrizeTable {
+    /// Percent of the pot per place, 1st place first; must add up to 100.
+    Tiered(Vec<u8>),
+    /// This many winners with equal shares.
+    Equal(u32),
+}
+
+/// Rules a box is opened with. Durations are in seconds; the percentages
+/// split every miner's stake and must add up to 100.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxConfig {
+    pub duration_secs: u64,
+    pub miner_duration_secs: u64,
+    pub prize_pct: u8,
+    pub creator_pct: u8,
+    pub admin_pct: u8,
+    pub min_miner_stake: TokenAmount,
+    pub max_miner_stake: Option<TokenAmount>,
+    pub max_miners: Option<u32>,
+    /// None for boxes opened before stake-weighted draws; they draw uniformly.
+    pub draw: Option<DrawMode>,
+    /// None means a single winner takes the whole pot.
+    pub prize_table: Option<PrizeTable>,
+    /// None means `Single`.
+    pub randomness: Option<RandomnessMode>,
+    /// Percent of the pot that rolls into the token's jackpot when nobody
+    /// joined; the rest goes back to the creator. None means all of it does.
+    pub rollover_pct: Option<u8>,
+}
+
+/// Admin-set limits a `BoxConfig` has to stay within.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxConfigBounds {
+    pub min_duration_secs: u64,
+    pub max_duration_secs: u64,
+    pub min_miner_duration_secs: u64,
+    pub min_admin_pct: u8,
+    pub max_creator_pct: u8,
+    pub max_miners: u32,
+}
+
+/// Canister id of a box. A distinct type from [`MinerId`] so a miner id
+/// can't be looked up among the boxes, or the other way round. Both are
+/// plain principals on the wire.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BoxId(pub Principal);
+
+/// Canister id of a miner.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MinerId(pub Principal);
+
+macro_rules! impl_id {
+    ($type:ident) => {
+        impl $type {
+            pub fn from_text(text: &str) -> Result<Self, String> {
+                Principal::from_text(text)
+                    .map($type)
+                    .map_err(|e| format!("Bad principal {}: {}", text, e))
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0.to_text())
+            }
+        }
+
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(self.0.as_slice().to_vec())
+            }
+
+            fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+                $type(Principal::from_slice(&bytes))
+            }
+
+            const BOUND: Bound = Bound::Bounded { max_size: 29, is_fixed_size: false };
+        }
+    };
+}
+
+impl_id!(BoxId);
+impl_id!(MinerId);
+
+/// A registered user's display name, at most [`MAX_NICKNAME_LEN`] characters.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Nickname(String);
+
+impl Nickname {
+    pub fn new(nickname: String) -> Result<Self, BackendError> {
+        if nickname.chars().count() > MAX_NICKNAME_LEN {
+            return Err(BackendError::NicknameTooLong { max_len: MAX_NICKNAME_LEN as u32 });
+        }
+        Ok(Nickname(nickname))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// An amount of a box's token in its smallest unit. Plain `nat` on the wire.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub struct TokenAmount(pub Nat);
+
+impl From<Nat> for TokenAmount {
+    fn from(amount: Nat) -> Self {
+        TokenAmount(amount)
+    }
+}
+
+impl From<TokenAmount> for Nat {
+    fn from(amount: TokenAmount) -> Self {
+        amount.0
+    }
+}
+
+impl std::ops::Add for TokenAmount {
+    type Output = TokenAmount;
+
+    fn add(self, other: TokenAmount) -> TokenAmount {
+        TokenAmount(self.0 + other.0)
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct User {
-    pub nickname: String,    
+    pub nickname: Nickname,
 }
 
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct BoxInfo {
-    pub user: String, 
-    pub canister_id: String,    
+    pub user: Principal,
+    pub canister_id: BoxId,
     pub reg_date: u64,
     pub end_date: u64,
-    pub is_end: bool
+    pub is_end: bool,
+    /// None for boxes opened before v3; ask the box canister instead.
+    pub subaccount: Option<Vec<u8>>,
+    /// Ledger the box and its miners are paid in. Set on every box since v4.
+    pub ledger: Option<Principal>,
+    /// None for boxes opened with the old global rules.
+    pub config: Option<BoxConfig>,
+    /// sha256 of the seed committed to at creation, in commit-reveal boxes.
+    pub seed_commit: Option<Vec<u8>>,
+    /// The recurring template that opened the box, if any.
+    pub template_id: Option<u64>,
 }
 
 
+
 #[derive(CandidType, Deserialize, Clone)]
 pub struct BoxWithCount {
     pub username: String,
+    /// Every miner the box has had, whatever its status.
     pub miner_count: u32,
     pub end_date: u64,
     pub reg_date: u64,
-    pub canister_id: String,
-    pub user_miners: Vec<Miner>
+    pub canister_id: BoxId,
+    pub user_miners: Vec<Miner>,
+    pub ledger_canister_id: Principal,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+    pub token_fee: Nat,
+    pub config: BoxConfig,
+    pub seed_commit: Option<Vec<u8>>,
+    pub active_miner_count: u32,
+    /// Summed over eligible miners; see [`Miner::is_eligible`].
+    pub total_stake: TokenAmount,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Miner {
-    pub user: String, 
-    pub box_id: String,
-    pub canister_id: String,    
+    pub user: Principal,
+    pub box_id: BoxId,
+    pub canister_id: MinerId,
     pub reg_date: u64,
     pub end_date: u64,
-    pub is_end: bool
+    pub is_end: bool,
+    /// None for miners opened before v3; ask the miner canister instead.
+    pub subaccount: Option<Vec<u8>>,
+    /// What the miner paid in. None for miners opened before stakes were
+    /// recorded; stake-weighted draws count them at the box's minimum stake.
+    pub stake: Option<TokenAmount>,
+    /// How its settlement ended. None until then; see [`Miner::status_at`].
+    pub status: Option<MinerStatus>,
+}
+
+/// Where a miner is in its life.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum MinerStatus {
+    /// Running and paying into its box.
+    Active,
+    /// Past its end date, settlement not finished yet.
+    Expired,
+    /// Its stake was split between the admin, the box creator and the pot.
+    Settled,
+    /// Its settlement gave up before its share reached the pot.
+    Failed,
+    /// Its box could no longer take it, so its stake went back to its owner.
+    Refunded,
+}
+
+impl Miner {
+    pub fn status_at(&self, now: u64) -> MinerStatus {
+        match &self.status {
+            Some(status) => status.clone(),
+            None if self.is_end => MinerStatus::Settled,
+            None if self.end_date <= now => MinerStatus::Expired,
+            None => MinerStatus::Active,
+        }
+    }
+
+    /// A miner takes part in its box's draw unless its stake never reached
+    /// the pot. Active and expired miners are still due to pay in; the box
+    /// settles only after them.
+    pub fn is_eligible(&self, now: u64) -> bool {
+        !matches!(self.status_at(now), MinerStatus::Failed | MinerStatus::Refunded)
+    }
+}
+
+/// What a job does and what for. Settlements are keyed by it too.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobKind {
+    MinerEnd(MinerId),
+    BoxEnd(BoxId),
+    /// The draw of a token's jackpot, by the token's ledger.
+    JackpotDraw(Principal),
+    TemplateBox { template_id: u64 },
+}
+
+/// Deferred work kept by the backend scheduler.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub due_at: u64,
+    pub attempts: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PayoutPurpose {
+    AdminTax,
+    CreatorShare,
+    PrizePool,
+    Prize,
+    Refund,
+    Rollover,
+    JackpotContribution,
+    JackpotPrize,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PayoutStatus {
+    Pending,
+    Done { block_index: Nat },
+    Failed { reason: String },
+}
+
+/// One ledger transfer of a settlement. `memo` and `created_at_time` are fixed
+/// when the payout is planned so a retried transfer is deduplicated by the ledger.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PayoutRecord {
+    pub purpose: PayoutPurpose,
+    pub from_subaccount: Vec<u8>,
+    pub to: ICRCAccount,
+    pub amount: TokenAmount,
+    /// Fee sent with the transfer. None for payouts planned before fees were
+    /// sent explicitly; those let the ledger charge its current fee.
+    pub fee: Option<Nat>,
+    pub memo: Vec<u8>,
+    pub created_at_time: u64,
+    pub status: PayoutStatus,
+}
+
+/// Pending -> PayoutsIssued -> Settled | Failed
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum SettlementStatus {
+    Pending,
+    PayoutsIssued,
+    Settled,
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Settlement {
+    pub kind: JobKind,
+    pub status: SettlementStatus,
+    pub payouts: Vec<PayoutRecord>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DrawCandidate {
+    pub miner_id: MinerId,
+    pub stake: Option<TokenAmount>,
+    /// What the draw counted: the stake, or 1 in uniform draws.
+    pub weight: Nat,
+}
+
+/// The two halves of a commit-reveal seed, published when the box closes.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SeedReveal {
+    pub commit: Vec<u8>,
+    pub committed_seed: Vec<u8>,
+    pub close_seed: Vec<u8>,
+}
+
+/// Everything needed to replay a box's draw with [`draw::verify`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DrawTranscript {
+    pub box_id: BoxId,
+    pub algorithm_version: u32,
+    /// The seed the draw ran on: the `raw_rand` bytes, or in commit-reveal
+    /// boxes the combination of `reveal`'s two seeds.
+    pub seed: Vec<u8>,
+    pub reveal: Option<SeedReveal>,
+    pub mode: DrawMode,
+    /// Snapshot of the miners in the order the draw saw them.
+    pub candidates: Vec<DrawCandidate>,
+    pub places: u32,
+    /// Indexes into `candidates`, 1st place first.
+    pub winners: Vec<u32>,
+    pub drawn_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxWinner {
+    /// 1 for first place.
+    pub place: u32,
+    pub miner_id: MinerId,
+    pub user: Principal,
+    /// Prize after the ledger fee; 0 when the share did not cover the fee.
+    pub amount: TokenAmount,
+}
+
+/// How a box was decided, fixed when its payouts are planned. The transfers
+/// themselves are tracked by the box's `Settlement`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxResult {
+    pub box_id: BoxId,
+    pub winners: Vec<BoxWinner>,
+    /// Set instead of winners when nobody joined: what went back to the creator.
+    pub refund: Option<TokenAmount>,
+    pub decided_at: u64,
+    pub reveal: Option<SeedReveal>,
+    /// What an empty box paid into the jackpot, by its `rollover_pct`.
+    pub rollover: Option<TokenAmount>,
+}
+
+/// Admin-set rules of the jackpot draws.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JackpotSettings {
+    pub draw_interval_secs: u64,
+    /// Percent of every settled miner stake paid into the jackpot before
+    /// the stake is split. None means nothing is.
+    pub contribution_pct: Option<u8>,
+}
+
+/// The progressive jackpot of one token. Its funds sit in the backend's
+/// jackpot subaccount on that token's ledger; see [`subaccount::jackpot`].
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Jackpot {
+    pub ledger: Principal,
+    /// What was paid in minus what was paid out, refreshed from the ledger
+    /// at every draw.
+    pub balance: TokenAmount,
+    /// Miners running since then take part in the next draw.
+    pub period_start: u64,
+    pub next_draw_at: u64,
+    /// Draws that had a winner.
+    pub rounds: u32,
+    /// Winner of the draw whose payout is in flight.
+    pub pending_winner: Option<BoxWinner>,
+    /// Transcript of that draw.
+    pub pending_draw: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JackpotEventKind {
+    Rollover { box_id: BoxId, amount: TokenAmount },
+    Contribution { miner_id: MinerId, amount: TokenAmount },
+    /// `draw_id` is None for draws made before transcripts were kept.
+    Draw { round: u32, winner: BoxWinner, draw_id: Option<u64> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JackpotEvent {
+    pub ledger: Principal,
+    pub kind: JackpotEventKind,
+    pub timestamp: u64,
+}
+
+/// Everything needed to replay a jackpot draw with [`draw::verify_jackpot`].
+/// `id` is the position in the jackpot draw log.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JackpotDrawTranscript {
+    pub id: u64,
+    pub ledger: Principal,
+    pub algorithm_version: u32,
+    pub seed: Vec<u8>,
+    /// Miners running at some point since then were candidates.
+    pub period_start: u64,
+    /// Weighted by stake, in the order the draw saw them.
+    pub candidates: Vec<DrawCandidate>,
+    /// Index into `candidates`.
+    pub winner: u32,
+    pub drawn_at: u64,
+}
+
+/// Reply of `get_jackpot`: the jackpot and its latest events, newest first.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JackpotView {
+    pub jackpot: Jackpot,
+    pub history: Vec<JackpotEvent>,
+}
+
+/// Where a box's pot is paid from.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum BoxFunding {
+    /// The owner's ICRC-2 allowance to the backend.
+    Allowance,
+    /// The backend's own account; admin templates only.
+    Treasury,
+}
+
+/// A box the backend opens again each time the previous one has ended.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxTemplate {
+    pub id: u64,
+    /// Creator of every box the template opens.
+    pub owner: Principal,
+    pub ledger: Principal,
+    pub award: TokenAmount,
+    pub config: BoxConfig,
+    pub funding: BoxFunding,
+    pub enabled: bool,
+    /// The box running now; None while the next one is being opened.
+    pub current_box: Option<BoxId>,
+    pub boxes_opened: u32,
+    /// Why the last attempt to open a box failed.
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum JournalReason {
+    EntryFee,
+    AdminTax,
+    CreatorShare,
+    PrizePool,
+    Prize,
+    Refund,
+    Rollover,
+    JackpotContribution,
+    JackpotPrize,
+}
+
+impl From<PayoutPurpose> for JournalReason {
+    fn from(purpose: PayoutPurpose) -> Self {
+        match purpose {
+            PayoutPurpose::AdminTax => JournalReason::AdminTax,
+            PayoutPurpose::CreatorShare => JournalReason::CreatorShare,
+            PayoutPurpose::PrizePool => JournalReason::PrizePool,
+            PayoutPurpose::Prize => JournalReason::Prize,
+            PayoutPurpose::Refund => JournalReason::Refund,
+            PayoutPurpose::Rollover => JournalReason::Rollover,
+            PayoutPurpose::JackpotContribution => JournalReason::JackpotContribution,
+            PayoutPurpose::JackpotPrize => JournalReason::JackpotPrize,
+        }
+    }
+}
+
+/// A ledger movement made by the backend. `id` is the position in the journal.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub reason: JournalReason,
+    pub amount: TokenAmount,
+    pub from: ICRCAccount,
+    pub to: ICRCAccount,
+    pub block_index: Nat,
+    pub timestamp: u64,
+    pub box_id: Option<BoxId>,
+    pub miner_id: Option<MinerId>,
+    /// Ledger the movement happened on; None for entries written before v4.
+    pub ledger: Option<Principal>,
+}
+
+/// Error returned by the backend's endpoints.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum BackendError {
+    NotRegistered,
+    AlreadyRegistered,
+    NicknameTooLong { max_len: u32 },
+    InvalidPrincipal(String),
+    NotAuthorized,
+    TokenNotSupported { ledger: Principal },
+    BelowMinimum { min: TokenAmount },
+    InsufficientAllowance { have: TokenAmount, need: TokenAmount },
+    /// The ledger turned the transfer down; nothing moved.
+    Ledger(TransferError),
+    /// The ledger could not be reached or answered garbage.
+    LedgerCall(String),
+    CanisterCreation(String),
+    BoxNotFound,
+    TemplateNotFound,
+    SettlementNotFound,
+    /// Only settlements that gave up can be requeued.
+    SettlementNotFailed,
+    BoxEnded,
+    /// A miner opened now would run past the end of its box.
+    MinerOutlivesBox { box_end_date: u64 },
+    Blocked,
+    InvalidBoxConfig(String),
+    InvalidJackpotSettings(String),
+    AboveMaximum { max: TokenAmount },
+    BoxFull { max_miners: u32 },
+    /// Paid, but setup failed; the payment was sent back minus the ledger fee.
+    Refunded { reason: Box<BackendError>, refund_block: Nat },
+    /// Paid, setup failed and so did the refund; the funds wait in `subaccount`.
+    RefundFailed { reason: Box<BackendError>, refund_error: String, subaccount: Vec<u8> },
+}
+
+impl BackendError {
+    /// Maps an `icrc2_transfer_from` rejection; `need` is amount plus fee.
+    pub fn from_transfer_from(error: ICRC2TransferFromError, need: TokenAmount) -> Self {
+        let error = match error {
+            ICRC2TransferFromError::InsufficientAllowance { allowance } => {
+                return BackendError::InsufficientAllowance { have: allowance.into(), need }
+            }
+            ICRC2TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            ICRC2TransferFromError::BadBurn { min_burn_amount } => TransferError::BadBurn { min_burn_amount },
+            ICRC2TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+            ICRC2TransferFromError::TooOld => TransferError::TooOld,
+            ICRC2TransferFromError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            ICRC2TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            ICRC2TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+            ICRC2TransferFromError::GenericError { error_code, message } => TransferError::GenericError {
+                error_code: u64::try_from(error_code.0).unwrap_or(u64::MAX),
+                message,
+            },
+        };
+        BackendError::Ledger(error)
+    }
+}
+
+/// Stores a candid record in stable memory, capped at `$max_size` encoded bytes.
+macro_rules! impl_bounded_storable {
+    ($type:ty, $max_size:expr) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).expect(concat!("Failed to encode ", stringify!($type))))
+            }
+
+            fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+                Decode!(bytes.as_ref(), $type).expect(concat!("Failed to decode ", stringify!($type)))
+            }
+
+            const BOUND: Bound = Bound::Bounded { max_size: $max_size, is_fixed_size: false };
+        }
+    };
+}
+
+/// Stores a candid record of any size in stable memory.
+macro_rules! impl_unbounded_storable {
+    ($type:ty) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(Encode!(self).expect(concat!("Failed to encode ", stringify!($type))))
+            }
+
+            fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+                Decode!(bytes.as_ref(), $type).expect(concat!("Failed to decode ", stringify!($type)))
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+impl_bounded_storable!(StateEnvelope, 64);
+impl_bounded_storable!(Config, 1024);
+impl_bounded_storable!(TokenInfo, 512);
+impl_bounded_storable!(BoxConfigBounds, 128);
+impl_bounded_storable!(User, 512);
+impl_bounded_storable!(BoxInfo, 1024);
+impl_bounded_storable!(Miner, 1024);
+impl_bounded_storable!(Job, 256);
+impl_unbounded_storable!(Settlement);
+impl_bounded_storable!(JournalEntry, 1024);
+impl_bounded_storable!(BoxResult, 4096);
+impl_unbounded_storable!(DrawTranscript);
+impl_bounded_storable!(JackpotSettings, 64);
+impl_bounded_storable!(Jackpot, 512);
+impl_bounded_storable!(JackpotEvent, 512);
+impl_unbounded_storable!(JackpotDrawTranscript);
+impl_unbounded_storable!(BoxTemplate);
+
+impl_bounded_storable!(legacy::UserV1, 512);
+impl_bounded_storable!(legacy::BoxInfoV4, 1024);
+impl_bounded_storable!(legacy::MinerV4, 1024);
+impl_bounded_storable!(JobKind, 128);
+impl_bounded_storable!(legacy::JobV4, 256);
+impl_bounded_storable!(legacy::SettlementV4, 4096);
+impl_bounded_storable!(legacy::JobV5, 256);
+impl_unbounded_storable!(legacy::SettlementV5);
+impl_bounded_storable!(legacy::JournalEntryV4, 1024);
+impl_bounded_storable!(legacy::BoxResultV4, 4096);
+impl_unbounded_storable!(legacy::DrawTranscriptV4);
+
+fn parse_principal(text: &str) -> Result<Principal, String> {
+    Principal::from_text(text).map_err(|e| format!("Bad principal {}: {}", text, e))
+}
+
+// v4 records named principals by their text; v5 stores the principals.
+// A text that does not parse fails the conversion of the whole record.
+
+// Nicknames had no limit before v5; longer ones are cut to it.
+impl From<legacy::UserV1> for User {
+    fn from(user: legacy::UserV1) -> Self {
+        let nickname = user.nickname.chars().take(MAX_NICKNAME_LEN).collect();
+        User { nickname: Nickname::new(nickname).expect("Nickname was cut to the limit") }
+    }
+}
+
+impl TryFrom<legacy::BoxInfoV4> for BoxInfo {
+    type Error = String;
+
+    fn try_from(box_info: legacy::BoxInfoV4) -> Result<Self, String> {
+        Ok(BoxInfo {
+            user: parse_principal(&box_info.user)?,
+            canister_id: BoxId::from_text(&box_info.canister_id)?,
+            reg_date: box_info.reg_date,
+            end_date: box_info.end_date,
+            is_end: box_info.is_end,
+            subaccount: box_info.subaccount,
+            ledger: box_info.ledger,
+            config: box_info.config,
+            seed_commit: box_info.seed_commit,
+            template_id: None,
+        })
+    }
+}
+
+impl TryFrom<legacy::MinerV4> for Miner {
+    type Error = String;
+
+    fn try_from(miner: legacy::MinerV4) -> Result<Self, String> {
+        Ok(Miner {
+            user: parse_principal(&miner.user)?,
+            box_id: BoxId::from_text(&miner.box_id)?,
+            canister_id: MinerId::from_text(&miner.canister_id)?,
+            reg_date: miner.reg_date,
+            end_date: miner.end_date,
+            is_end: miner.is_end,
+            subaccount: miner.subaccount,
+            stake: miner.stake.map(TokenAmount),
+            status: miner.status,
+        })
+    }
+}
+
+impl TryFrom<legacy::JobV4> for legacy::JobV5 {
+    type Error = String;
+
+    fn try_from(job: legacy::JobV4) -> Result<Self, String> {
+        Ok(legacy::JobV5 {
+            id: job.id,
+            kind: job.kind,
+            target: parse_principal(&job.target)?,
+            due_at: job.due_at,
+            attempts: job.attempts,
+        })
+    }
+}
+
+impl TryFrom<legacy::SettlementV4> for legacy::SettlementV5 {
+    type Error = String;
+
+    fn try_from(settlement: legacy::SettlementV4) -> Result<Self, String> {
+        Ok(legacy::SettlementV5 {
+            kind: settlement.kind,
+            target: parse_principal(&settlement.target)?,
+            status: settlement.status,
+            payouts: settlement.payouts,
+            updated_at: settlement.updated_at,
+        })
+    }
+}
+
+// v5 jobs kept what they were for in a bare `target`; v6 folds it into the kind.
+impl legacy::JobKindV5 {
+    pub fn with_target(self, target: Principal) -> JobKind {
+        match self {
+            legacy::JobKindV5::MinerEnd => JobKind::MinerEnd(MinerId(target)),
+            legacy::JobKindV5::BoxEnd => JobKind::BoxEnd(BoxId(target)),
+            legacy::JobKindV5::JackpotDraw => JobKind::JackpotDraw(target),
+            legacy::JobKindV5::TemplateBox { template_id } => JobKind::TemplateBox { template_id },
+        }
+    }
+}
+
+impl From<legacy::JobV5> for Job {
+    fn from(job: legacy::JobV5) -> Self {
+        Job {
+            id: job.id,
+            kind: job.kind.with_target(job.target),
+            due_at: job.due_at,
+            attempts: job.attempts,
+        }
+    }
+}
+
+impl From<legacy::SettlementV5> for Settlement {
+    fn from(settlement: legacy::SettlementV5) -> Self {
+        Settlement {
+            kind: settlement.kind.with_target(settlement.target),
+            status: settlement.status,
+            payouts: settlement.payouts,
+            updated_at: settlement.updated_at,
+        }
+    }
+}
+
+impl TryFrom<legacy::JournalEntryV4> for JournalEntry {
+    type Error = String;
+
+    fn try_from(entry: legacy::JournalEntryV4) -> Result<Self, String> {
+        Ok(JournalEntry {
+            id: entry.id,
+            reason: entry.reason,
+            amount: entry.amount.into(),
+            from: entry.from,
+            to: entry.to,
+            block_index: entry.block_index,
+            timestamp: entry.timestamp,
+            box_id: entry.box_id.as_deref().map(BoxId::from_text).transpose()?,
+            miner_id: entry.miner_id.as_deref().map(MinerId::from_text).transpose()?,
+            ledger: entry.ledger,
+        })
+    }
+}
+
+impl TryFrom<legacy::DrawTranscriptV4> for DrawTranscript {
+    type Error = String;
+
+    fn try_from(transcript: legacy::DrawTranscriptV4) -> Result<Self, String> {
+        let candidates = transcript
+            .candidates
+            .into_iter()
+            .map(|candidate| {
+                Ok(DrawCandidate {
+                    miner_id: MinerId::from_text(&candidate.miner_id)?,
+                    stake: candidate.stake.map(TokenAmount),
+                    weight: candidate.weight,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(DrawTranscript {
+            box_id: BoxId::from_text(&transcript.box_id)?,
+            algorithm_version: transcript.algorithm_version,
+            seed: transcript.seed,
+            reveal: transcript.reveal,
+            mode: transcript.mode,
+            candidates,
+            places: transcript.places,
+            winners: transcript.winners,
+            drawn_at: transcript.drawn_at,
+        })
+    }
+}
+
+impl TryFrom<legacy::BoxResultV4> for BoxResult {
+    type Error = String;
+
+    fn try_from(result: legacy::BoxResultV4) -> Result<Self, String> {
+        let winners = result
+            .winners
+            .into_iter()
+            .map(|winner| {
+                Ok(BoxWinner {
+                    place: winner.place,
+                    miner_id: MinerId::from_text(&winner.miner_id)?,
+                    user: parse_principal(&winner.user)?,
+                    amount: TokenAmount(winner.amount),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(BoxResult {
+            box_id: BoxId::from_text(&result.box_id)?,
+            winners,
+            refund: result.refund.map(TokenAmount),
+            decided_at: result.decided_at,
+            reveal: result.reveal,
+            rollover: None,
+        })
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -152,6 +1015,12 @@ pub struct ICRC2AllowanceArgs {
     pub spender: ICRCAccount,
 }
 
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ICRC2Allowance {
     pub allowance: Nat,
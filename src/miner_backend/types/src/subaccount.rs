@@ -0,0 +1,64 @@
+use sha2::{Digest, Sha256};
+
+/// What a backend subaccount holds funds for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SubaccountKind {
+    Box,
+    Miner,
+    Jackpot,
+}
+
+impl SubaccountKind {
+    fn tag(self) -> u8 {
+        match self {
+            SubaccountKind::Box => 1,
+            SubaccountKind::Miner => 2,
+            SubaccountKind::Jackpot => 3,
+        }
+    }
+}
+
+const DOMAIN: &[u8] = b"miner-backend-subaccount";
+
+/// Subaccount for the `index`-th box or miner: SHA-256 over a length-prefixed
+/// domain separator, the kind tag and the big-endian index. Distinct
+/// (kind, index) pairs only collide if SHA-256 does.
+pub fn derive(kind: SubaccountKind, index: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([DOMAIN.len() as u8]);
+    hasher.update(DOMAIN);
+    hasher.update([kind.tag()]);
+    hasher.update(index.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// Subaccount holding the jackpots. There is one jackpot per token, and the
+/// ledgers keep their balances apart, so they all share it.
+pub fn jackpot() -> [u8; 32] {
+    derive(SubaccountKind::Jackpot, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // A million boxes and a million miners is far more than the counter will
+    // hand out in practice, and still runs in well under a minute. The top
+    // of the range checks that the index is not truncated. The jackpot only
+    // ever uses index 0.
+    fn indexes() -> impl Iterator<Item = u64> {
+        (0..1_000_000).chain(u64::MAX - 1_000..=u64::MAX)
+    }
+
+    #[test]
+    fn subaccounts_are_unique_across_kinds_and_indexes() {
+        let mut seen = HashSet::from([jackpot()]);
+        for index in indexes() {
+            let (box_sub, miner_sub) = (derive(SubaccountKind::Box, index), derive(SubaccountKind::Miner, index));
+            assert_ne!(box_sub, miner_sub, "box and miner {} share a subaccount", index);
+            assert!(seen.insert(box_sub), "box {} collides", index);
+            assert!(seen.insert(miner_sub), "miner {} collides", index);
+        }
+    }
+}
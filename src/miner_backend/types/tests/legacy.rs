@@ -0,0 +1,162 @@
+//! Records as earlier state versions stored them, decoded with the frozen
+//! shapes in `types::legacy` and carried over to the current ones.
+use candid::{Nat, Principal};
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+use types::legacy::*;
+use types::*;
+
+fn principal(byte: u8) -> Principal {
+    Principal::from_slice(&[byte; 10])
+}
+
+fn decode<T: Storable>(bytes: &'static [u8]) -> T {
+    T::from_bytes(Cow::Borrowed(bytes))
+}
+
+fn ledger() -> Principal {
+    Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+}
+
+#[test]
+fn state_v1_decodes_and_converts() {
+    let (users, boxes, miners, box_miner, sub_index): StateV1 =
+        candid::decode_args(include_bytes!("fixtures/state_v1.bin")).unwrap();
+
+    assert_eq!(sub_index, 2);
+    assert_eq!(box_miner.get(&principal(20).to_text()), Some(&principal(10).to_text()));
+
+    let users: Vec<User> = users.into_values().map(User::from).collect();
+    assert_eq!(users[0].nickname.as_str(), "alice");
+    // Nicknames had no limit in v1.
+    assert_eq!(users[1].nickname.as_str(), "b".repeat(MAX_NICKNAME_LEN));
+
+    let box_info = BoxInfo::try_from(BoxInfoV4::from(boxes[&principal(10).to_text()].clone())).unwrap();
+    assert_eq!(box_info.user, principal(1));
+    assert_eq!(box_info.canister_id, BoxId(principal(10)));
+    assert_eq!((box_info.reg_date, box_info.end_date, box_info.is_end), (1_000, 2_000, false));
+    assert!(box_info.subaccount.is_none() && box_info.ledger.is_none() && box_info.config.is_none());
+
+    let miner = Miner::try_from(MinerV4::from(miners[&principal(20).to_text()].clone())).unwrap();
+    assert_eq!(miner.user, principal(2));
+    assert_eq!(miner.box_id, BoxId(principal(10)));
+    assert_eq!(miner.canister_id, MinerId(principal(20)));
+    assert!(miner.is_end && miner.stake.is_none() && miner.status.is_none());
+}
+
+// v2 and v3 kept their records in the v4 maps; the fields added since
+// decode as None.
+#[test]
+fn box_info_v2_and_v3_decode_as_v4() {
+    let v2 = decode::<BoxInfoV4>(include_bytes!("fixtures/box_info_v2.bin"));
+    assert_eq!((v2.user, v2.canister_id), (principal(1).to_text(), principal(10).to_text()));
+    assert!(v2.subaccount.is_none() && v2.ledger.is_none() && v2.config.is_none());
+
+    let v3 = decode::<BoxInfoV4>(include_bytes!("fixtures/box_info_v3.bin"));
+    assert_eq!(v3.subaccount, Some(vec![1; 32]));
+    assert!(v3.ledger.is_none() && v3.config.is_none());
+}
+
+#[test]
+fn miner_v2_and_v3_decode_as_v4() {
+    let v2 = decode::<MinerV4>(include_bytes!("fixtures/miner_v2.bin"));
+    assert_eq!((v2.box_id, v2.canister_id), (principal(10).to_text(), principal(20).to_text()));
+    assert!(v2.subaccount.is_none() && v2.stake.is_none() && v2.status.is_none());
+
+    let v3 = decode::<MinerV4>(include_bytes!("fixtures/miner_v3.bin"));
+    assert_eq!(v3.subaccount, Some(vec![2; 32]));
+    assert!(v3.stake.is_none() && v3.status.is_none());
+}
+
+#[test]
+fn user_v4_converts() {
+    let user = User::from(decode::<UserV4>(include_bytes!("fixtures/user_v4.bin")));
+    assert_eq!(user.nickname.as_str(), "alice");
+}
+
+#[test]
+fn box_info_v4_converts() {
+    let box_info = BoxInfo::try_from(decode::<BoxInfoV4>(include_bytes!("fixtures/box_info_v4.bin"))).unwrap();
+    assert_eq!(box_info.user, principal(1));
+    assert_eq!(box_info.canister_id, BoxId(principal(10)));
+    assert_eq!(box_info.subaccount, Some(vec![1; 32]));
+    assert_eq!(box_info.ledger, Some(ledger()));
+    assert_eq!(box_info.seed_commit, Some(vec![9; 32]));
+    assert_eq!(box_info.template_id, None);
+
+    let config = box_info.config.unwrap();
+    assert_eq!((config.prize_pct, config.creator_pct, config.admin_pct), (25, 65, 10));
+    assert_eq!(config.min_miner_stake, TokenAmount(Nat::from(100_000u32)));
+    assert_eq!(config.max_miners, Some(50));
+    assert!(matches!(config.prize_table, Some(PrizeTable::Tiered(shares)) if shares == vec![70, 30]));
+    assert_eq!(config.randomness, Some(RandomnessMode::CommitReveal));
+    // v4 configs had no rollover share.
+    assert_eq!(config.rollover_pct, None);
+}
+
+#[test]
+fn miner_v4_converts() {
+    let miner = Miner::try_from(decode::<MinerV4>(include_bytes!("fixtures/miner_v4.bin"))).unwrap();
+    assert_eq!(miner.user, principal(2));
+    assert_eq!(miner.box_id, BoxId(principal(10)));
+    assert_eq!(miner.canister_id, MinerId(principal(20)));
+    assert_eq!(miner.stake, Some(TokenAmount(Nat::from(250_000u32))));
+    assert_eq!(miner.status, Some(MinerStatus::Settled));
+}
+
+#[test]
+fn job_v4_converts() {
+    let job: Job = JobV5::try_from(decode::<JobV4>(include_bytes!("fixtures/job_v4.bin"))).unwrap().into();
+    assert_eq!(job.id, 7);
+    assert_eq!(job.kind, JobKind::BoxEnd(BoxId(principal(10))));
+    assert_eq!((job.due_at, job.attempts), (2_000, 3));
+}
+
+#[test]
+fn settlement_v4_converts() {
+    let settlement: Settlement = SettlementV5::try_from(decode::<SettlementV4>(include_bytes!("fixtures/settlement_v4.bin"))).unwrap().into();
+    assert_eq!(settlement.kind, JobKind::BoxEnd(BoxId(principal(10))));
+    assert_eq!(settlement.status, SettlementStatus::Settled);
+    assert_eq!(settlement.payouts.len(), 1);
+    assert_eq!(settlement.payouts[0].purpose, PayoutPurpose::Prize);
+    assert_eq!(settlement.payouts[0].status, PayoutStatus::Done { block_index: Nat::from(42u32) });
+}
+
+#[test]
+fn journal_entry_v4_converts() {
+    let entry = JournalEntry::try_from(decode::<JournalEntryV4>(include_bytes!("fixtures/journal_entry_v4.bin"))).unwrap();
+    assert_eq!(entry.id, 3);
+    assert_eq!(entry.reason, JournalReason::Prize);
+    assert_eq!(entry.amount, TokenAmount(Nat::from(240_000u32)));
+    assert_eq!(entry.box_id, Some(BoxId(principal(10))));
+    assert_eq!(entry.miner_id, Some(MinerId(principal(20))));
+    assert_eq!(entry.ledger, Some(ledger()));
+}
+
+#[test]
+fn draw_transcript_v4_converts() {
+    let transcript = DrawTranscript::try_from(decode::<DrawTranscriptV4>(include_bytes!("fixtures/draw_transcript_v4.bin"))).unwrap();
+    assert_eq!(transcript.box_id, BoxId(principal(10)));
+    assert_eq!(transcript.candidates.len(), 1);
+    assert_eq!(transcript.candidates[0].miner_id, MinerId(principal(20)));
+    assert_eq!(transcript.candidates[0].stake, Some(TokenAmount(Nat::from(250_000u32))));
+    assert_eq!((transcript.places, transcript.winners), (2, vec![0]));
+}
+
+#[test]
+fn box_result_v4_converts() {
+    let result = BoxResult::try_from(decode::<BoxResultV4>(include_bytes!("fixtures/box_result_v4.bin"))).unwrap();
+    assert_eq!(result.box_id, BoxId(principal(10)));
+    assert_eq!(result.winners.len(), 1);
+    assert_eq!(result.winners[0].miner_id, MinerId(principal(20)));
+    assert_eq!(result.winners[0].user, principal(2));
+    assert_eq!(result.winners[0].amount, TokenAmount(Nat::from(240_000u32)));
+    assert_eq!(result.rollover, None);
+}
+
+#[test]
+fn unparsable_principal_fails_the_conversion() {
+    let mut miner = decode::<MinerV4>(include_bytes!("fixtures/miner_v4.bin"));
+    miner.box_id = "not a principal".to_string();
+    assert!(Miner::try_from(miner).is_err());
+}
@@ -67,7 +67,8 @@ const BoxCard = ({ box, addBox, useBox }) => {
                     <strong>Creator:</strong> {box.username}
                     <p className="card-text">                              
                     <strong>Time Left:</strong> <Countdown endDateNano={box.end_date} /> <br />      
-                    <strong>Miner Count:</strong> {box.miner_count}
+                    <strong>Miner Count:</strong> {box.active_miner_count} active / {box.miner_count} total <br />
+                    <strong>Token:</strong> {box.token_symbol}
                     </p>           
                     {box.user_miners && box.user_miners.length > 0 ? (
                         <>
@@ -76,7 +77,7 @@ const BoxCard = ({ box, addBox, useBox }) => {
                                return (
                                 <div className="d-flex align-items-center gap-2 small" key={index}>
                                   <div className="fs-6 fw-light">
-                                    <strong>ID:</strong> {miner.canister_id}
+                                    <strong>ID:</strong> {miner.canister_id.toText()}
                                   </div>
                                   <strong><Countdown endDateNano={miner.end_date} /></strong>
                                 </div>
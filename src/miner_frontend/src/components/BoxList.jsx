@@ -4,7 +4,7 @@ import "bootstrap/dist/css/bootstrap.min.css";
 import BoxCard from "../components/BoxCard";
 import { useErrorDialog } from '../context/ErrorDialogContext';
 import { usePromptDialog } from '../context/PromptDialogContext';
-
+import { errorText } from '../errors';
 
 const BoxList = () => {
 
@@ -35,7 +35,7 @@ const BoxList = () => {
                 }
                 else
                 {
-                    showError(response.Err);
+                    showError(errorText(response.Err));
                 }
             } 
         }
@@ -48,7 +48,8 @@ const BoxList = () => {
     const useAnyBox = async (box) => {
         if(isAuthenticated && !needsRegistration)
         {
-            const result = await showPrompt("How much ICP will be as prize? (min 0.05 ICP):", "0.05");
+            const minStake = Number(box.config.min_miner_stake) / 10 ** box.token_decimals;
+            const result = await showPrompt(`How much ${box.token_symbol} will you stake? (min ${minStake} ${box.token_symbol}):`, String(minStake));
             if (result !== null) {
                 const response = await useBox(box, result);
                 console.log("useBox", response);
@@ -58,7 +59,7 @@ const BoxList = () => {
                 }
                 else
                 {
-                    showError(response.Err);
+                    showError(errorText(response.Err));
                 }
             } 
         }
@@ -1,5 +1,6 @@
 import React, { useState } from "react";
 import { useAuth } from "../context/AuthContext";
+import { errorText } from "../errors";
 
 const RegistrationForm = () => {
   const { register } = useAuth();
@@ -11,7 +12,7 @@ const RegistrationForm = () => {
     console.log("Start reg");
     const response = await register(nickname);
     console.log("setMessage", response);
-    setMessage(response == true ? "Registration successful!" : response);
+    setMessage(response == true ? "Registration successful!" : errorText(response));
   };
 
   return (
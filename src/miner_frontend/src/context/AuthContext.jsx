@@ -88,16 +88,7 @@ export const AuthProvider = ({ children }) => {
     if ("Ok" in response) {
         setUserData(response.Ok);
         setNeedsRegistration(false);
-        const balance = await actor.get_my_balance();
-        console.log("balance",balance);
-        if(balance.Ok)
-        {          
-          setBalance(Number(balance.Ok) / 100_000_000);
-        }
-        else
-        {
-          setBalance(0);
-        }
+        await refreshBalance(actor);
     }
     else
     {
@@ -111,6 +102,42 @@ export const AuthProvider = ({ children }) => {
     setUserActor(actor);
   };
 
+  // The balance shown is in the default token, the one boxes are created in.
+  const defaultToken = async () => {
+    const config = await miner_backend.get_config();
+    const tokens = await miner_backend.list_tokens();
+    return tokens.find((token) => token.ledger_canister_id.toText() === config.ledger_canister_id.toText());
+  };
+
+  const refreshBalance = async (actor) => {
+    const token = await defaultToken();
+    const balance = await actor.get_my_balance([]);
+    console.log("balance",balance);
+    if(token && 'Ok' in balance)
+    {
+      // A nat: split it before converting so large balances keep their digits.
+      const unit = 10n ** BigInt(token.decimals);
+      setBalance(Number(balance.Ok / unit) + Number(balance.Ok % unit) / 10 ** token.decimals);
+    }
+    else
+    {
+      setBalance(0);
+    }
+  };
+
+  // Parses a decimal amount such as "5.25" into the token's smallest unit
+  // without going through floats. Null when it is not a plain non-negative
+  // number or has more decimals than the token.
+  const toBaseUnits = (amount, decimals) => {
+    const match = /^(\d*)(?:\.(\d*))?$/.exec(String(amount).trim());
+    if(!match || (match[1] + (match[2] ?? "")) === "" || (match[2] ?? "").length > decimals)
+    {
+      return null;
+    }
+    const fraction = (match[2] ?? "").padEnd(decimals, "0");
+    return BigInt(match[1] || "0") * 10n ** BigInt(decimals) + BigInt(fraction || "0");
+  };
+
   const logout = async () => {
     const client = await AuthClient.create();
     await client.logout();
@@ -135,7 +162,7 @@ export const AuthProvider = ({ children }) => {
     }
   };
 
-  const approve = async (icp) => {   
+  const approve = async (amount, ledgerCanisterId, fee) => {   
     const provider =  getIdentityProvider();    
     console.log("provider",provider);
     const agent = await createAgent({
@@ -154,7 +181,7 @@ export const AuthProvider = ({ children }) => {
   
     const ledger = IcrcLedgerCanister.create({
       agent,
-      canisterId: "ryjl3-tyaaa-aaaaa-aaaba-cai",
+      canisterId: ledgerCanisterId,
     });    
     try
     {
@@ -164,7 +191,7 @@ export const AuthProvider = ({ children }) => {
       // from_subaccount: [],
       // created_at_time: null,
         spender: { owner: Principal.fromText(canisterId), subaccount: [] },
-        amount: icp + 10_000,
+        amount: amount + fee,
       // expected_allowance: null,
       // expires_at: null
       });          
@@ -179,20 +206,21 @@ export const AuthProvider = ({ children }) => {
   };
 
   const createBox = async (icp) => {   
-    const icp64 = icp * 100_000_000;
-    const approve_result = await approve(icp64);        
+    const token = await defaultToken();
+    if(!token)
+    {
+      return { Err: "Token is not available yet" };
+    }
+    const icp64 = toBaseUnits(icp, token.decimals);
+    if(icp64 === null)
+    {
+      return { Err: `Enter a number with at most ${token.decimals} decimals` };
+    }
+    const approve_result = await approve(icp64, token.ledger_canister_id, token.fee);        
     if(approve_result.Ok)
     {
-      const response = await userActor.create_box(icp64);
-      const balance = await userActor.get_my_balance();      
-      if(balance.Ok)
-      {          
-        setBalance(Number(balance.Ok) / 100_000_000);
-      }
-      else
-      {
-        setBalance(0);
-      }      
+      const response = await userActor.create_box(icp64, [token.ledger_canister_id], []);
+      await refreshBalance(userActor);
       return response;    
     }
     else
@@ -202,20 +230,16 @@ export const AuthProvider = ({ children }) => {
   };
 
   const useBox = async (box, icp) => {   
-    const icp64 = icp * 100_000_000;
-    const approve_result = await approve(icp64);    
+    const icp64 = toBaseUnits(icp, box.token_decimals);
+    if(icp64 === null)
+    {
+      return { Err: `Enter a number with at most ${box.token_decimals} decimals` };
+    }
+    const approve_result = await approve(icp64, box.ledger_canister_id, box.token_fee);    
     if(approve_result)
     {
       const response = await userActor.create_miner(box.canister_id, icp64);
-      const balance = await userActor.get_my_balance();      
-      if(balance.Ok)
-      {          
-        setBalance(Number(balance.Ok) / 100_000_000);
-      }
-      else
-      {
-        setBalance(0);
-      }            
+      await refreshBalance(userActor);
       return response;    
     }
     else
@@ -0,0 +1,37 @@
+// Backend errors are candid variants: an object with a single key.
+const messages = {
+  NotRegistered: () => "You need to register first.",
+  AlreadyRegistered: () => "You are already registered.",
+  NicknameTooLong: ({ max_len }) => `Nickname is longer than ${max_len} characters.`,
+  InvalidPrincipal: (text) => `Bad principal: ${text}`,
+  NotAuthorized: () => "You are not allowed to do this.",
+  TokenNotSupported: ({ ledger }) => `Token ${ledger.toText()} is not supported.`,
+  BelowMinimum: ({ min }) => `Amount is below the minimum of ${min}.`,
+  InsufficientAllowance: ({ have, need }) => `Not enough approved: ${have} of ${need}.`,
+  Ledger: (err) => `Ledger rejected the transfer: ${errorText(err)}`,
+  LedgerCall: (text) => `Ledger is unavailable: ${text}`,
+  CanisterCreation: (text) => `Could not create the canister: ${text}`,
+  BoxNotFound: () => "Box not found.",
+  TemplateNotFound: () => "Box template not found.",
+  BoxEnded: () => "This box has already ended.",
+  MinerOutlivesBox: () => "The box ends before a miner would.",
+  Blocked: () => "You are not allowed to open miners.",
+  InvalidBoxConfig: (text) => `Invalid box settings: ${text}`,
+  InvalidJackpotSettings: (text) => `Invalid jackpot settings: ${text}`,
+  AboveMaximum: ({ max }) => `Amount is above the maximum of ${max}.`,
+  BoxFull: ({ max_miners }) => `This box is full (${max_miners} miners).`,
+  Refunded: ({ reason }) => `${errorText(reason)} Your payment was refunded.`,
+  RefundFailed: ({ reason, refund_error }) => `${errorText(reason)} The refund failed too: ${refund_error}`,
+};
+
+export const errorText = (err) => {
+  if (typeof err === 'string') {
+    return err;
+  }
+  const [key] = Object.keys(err);
+  if (messages[key]) {
+    return messages[key](err[key]);
+  }
+  // bigints need converting before JSON.
+  return JSON.stringify(err, (key, value) => typeof value === 'bigint' ? value.toString() : value);
+};